@@ -0,0 +1,52 @@
+//! Measures `jitter` and `disaggregate` sampling origins and destinations from weighted subpoints,
+//! the vertices of a road network. Every trip samples from the alias table of its zones, so this
+//! shows how much time goes into sampling. Run with `cargo bench`, and compare against another
+//! version with criterion's `--save-baseline` and `--baseline` flags.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use odjitter::{disaggregate, jitter, load_zones, scrape_points, Options, Subsample};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+fn weighted_points(c: &mut Criterion) {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let subpoints = scrape_points("data/road_network.geojson", None).unwrap();
+    let options = || Options {
+        subsample_origin: Subsample::WeightedPoints(subpoints.clone()),
+        subsample_destination: Subsample::WeightedPoints(subpoints.clone()),
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("weighted points");
+    group.sample_size(20);
+    group.bench_function("jitter", |b| {
+        b.iter(|| {
+            jitter(
+                "data/od.csv",
+                &zones,
+                1,
+                "all".to_string(),
+                &mut StdRng::seed_from_u64(42),
+                options(),
+                |_| Ok(()),
+            )
+            .unwrap()
+        })
+    });
+    group.bench_function("disaggregate", |b| {
+        b.iter(|| {
+            disaggregate(
+                "data/od.csv",
+                &zones,
+                &mut StdRng::seed_from_u64(42),
+                options(),
+                |_| Ok(()),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, weighted_points);
+criterion_main!(benches);
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
+use geo::algorithm::area::Area;
+use geo::algorithm::chamberlain_duquette_area::ChamberlainDuquetteArea;
+use geo::algorithm::euclidean_distance::EuclideanDistance;
+use geo::algorithm::euclidean_length::EuclideanLength;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::algorithm::haversine_length::HaversineLength;
+use geo_types::{Line, Point, Polygon};
+use proj4rs::proj::Proj;
+
+/// The coordinate reference system of zones and subpoints. Distances in a geographic CRS like
+/// WGS84 are measured with the Haversine formula. Distances in a projected CRS, like the British
+/// National Grid, are measured in a straight line, so the projection should be suitable for the
+/// area covered. (Web Mercator isn't!)
+#[derive(Clone)]
+pub struct Crs {
+    name: String,
+    /// Only set for CRSs looked up by their code
+    epsg: Option<u32>,
+    proj: Proj,
+    /// The normalized proj4 parameters, used to compare CRSs
+    parameters: BTreeMap<String, String>,
+}
+
+impl Crs {
+    /// WGS84 longitude and latitude, the default.
+    pub fn wgs84() -> Crs {
+        Crs::from_epsg(4326).unwrap()
+    }
+
+    /// Parses a CRS from an EPSG code like `EPSG:27700`, an OGC URN like
+    /// `urn:ogc:def:crs:EPSG::27700`, or a proj4 string like `+proj=tmerc ...`.
+    pub fn parse(definition: &str) -> Result<Crs> {
+        let definition = definition.trim();
+        if definition.starts_with('+') {
+            return match Proj::from_proj_string(definition) {
+                Ok(proj) => Ok(Crs {
+                    name: definition.to_string(),
+                    epsg: None,
+                    proj,
+                    parameters: normalize_parameters(definition),
+                }),
+                Err(err) => bail!("Invalid proj4 string {definition}: {err}"),
+            };
+        }
+        let upper = definition.to_uppercase();
+        if upper.ends_with("CRS84") {
+            return Ok(Crs::wgs84());
+        }
+        if let Some((authority, code)) = upper.rsplit_once(':') {
+            if authority.trim_end_matches(':').ends_with("EPSG") {
+                if let Ok(code) = code.parse() {
+                    return Crs::from_epsg(code);
+                }
+            }
+        }
+        bail!("Unknown CRS {definition}; use a code like EPSG:27700 or a proj4 string")
+    }
+
+    /// Looks up a CRS by its EPSG code. Only some common codes are built in; use a proj4 string
+    /// for others.
+    pub fn from_epsg(code: u32) -> Result<Crs> {
+        let definition = match code {
+            4326 => "+proj=longlat +datum=WGS84 +no_defs".to_string(),
+            4258 => "+proj=longlat +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +no_defs".to_string(),
+            4269 => "+proj=longlat +datum=NAD83 +no_defs".to_string(),
+            // British National Grid
+            27700 => "+proj=tmerc +lat_0=49 +lon_0=-2 +k=0.9996012717 +x_0=400000 +y_0=-100000 +ellps=airy +towgs84=446.448,-125.157,542.06,0.15,0.247,0.842,-20.489 +units=m +no_defs".to_string(),
+            // Irish Transverse Mercator
+            2157 => "+proj=tmerc +lat_0=53.5 +lon_0=-8 +k=0.99982 +x_0=600000 +y_0=750000 +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs".to_string(),
+            // Lambert-93
+            2154 => "+proj=lcc +lat_0=46.5 +lon_0=3 +lat_1=49 +lat_2=44 +x_0=700000 +y_0=6600000 +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs".to_string(),
+            // ETRS89 / UTM
+            25828..=25838 => format!(
+                "+proj=utm +zone={} +ellps=GRS80 +towgs84=0,0,0,0,0,0,0 +units=m +no_defs",
+                code - 25800
+            ),
+            // WGS84 / UTM, north and south
+            32601..=32660 => format!(
+                "+proj=utm +zone={} +datum=WGS84 +units=m +no_defs",
+                code - 32600
+            ),
+            32701..=32760 => format!(
+                "+proj=utm +zone={} +south +datum=WGS84 +units=m +no_defs",
+                code - 32700
+            ),
+            _ => bail!("EPSG:{code} isn't built in; pass a proj4 string for it instead"),
+        };
+        let proj = match Proj::from_proj_string(&definition) {
+            Ok(proj) => proj,
+            Err(err) => bail!("Couldn't set up EPSG:{code}: {err}"),
+        };
+        Ok(Crs {
+            name: format!("EPSG:{code}"),
+            epsg: Some(code),
+            proj,
+            parameters: normalize_parameters(&definition),
+        })
+    }
+
+    /// The EPSG code or proj4 string describing this CRS.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The EPSG code of this CRS, unless it was defined by a proj4 string.
+    pub fn epsg_code(&self) -> Option<u32> {
+        self.epsg
+    }
+
+    /// Are coordinates longitude and latitude, instead of projected?
+    pub fn is_geographic(&self) -> bool {
+        self.proj.is_latlong()
+    }
+
+    /// Transforms a point in this CRS to WGS84.
+    pub fn to_wgs84(&self, point: Point<f64>) -> Result<Point<f64>> {
+        transform(self, wgs84(), point)
+    }
+
+    /// Transforms a point in WGS84 to this CRS.
+    pub fn from_wgs84(&self, point: Point<f64>) -> Result<Point<f64>> {
+        transform(wgs84(), self, point)
+    }
+
+    pub(crate) fn metric(&self) -> Metric {
+        if self.is_geographic() {
+            Metric::Haversine
+        } else {
+            Metric::Euclidean {
+                meters_per_unit: self.proj.to_meter(),
+            }
+        }
+    }
+}
+
+fn wgs84() -> &'static Crs {
+    static WGS84: OnceLock<Crs> = OnceLock::new();
+    WGS84.get_or_init(Crs::wgs84)
+}
+
+fn transform(from: &Crs, to: &Crs, point: Point<f64>) -> Result<Point<f64>> {
+    if from == to {
+        return Ok(point);
+    }
+    // proj4rs uses radians for longitude and latitude
+    let mut xyz = if from.is_geographic() {
+        (point.x().to_radians(), point.y().to_radians(), 0.0)
+    } else {
+        (point.x(), point.y(), 0.0)
+    };
+    if let Err(err) = proj4rs::transform::transform(&from.proj, &to.proj, &mut xyz) {
+        bail!(
+            "Couldn't transform {point:?} from {} to {}: {err}",
+            from.name,
+            to.name
+        );
+    }
+    if to.is_geographic() {
+        Ok(Point::new(xyz.0.to_degrees(), xyz.1.to_degrees()))
+    } else {
+        Ok(Point::new(xyz.0, xyz.1))
+    }
+}
+
+impl std::fmt::Debug for Crs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Crs({})", self.name)
+    }
+}
+
+/// Two CRSs are equal if they have the same projection parameters, even if one is an EPSG code
+/// and the other an equivalent proj4 string.
+impl PartialEq for Crs {
+    fn eq(&self, other: &Self) -> bool {
+        self.parameters == other.parameters
+    }
+}
+
+/// Splits a proj4 string into its parameters, so equivalent definitions compare equal: the order,
+/// numeric formatting and parameters that don't affect coordinates are ignored, units default to
+/// meters, and some common datums are expanded to their ellipsoid and transformation to WGS84.
+fn normalize_parameters(definition: &str) -> BTreeMap<String, String> {
+    let mut parameters = BTreeMap::new();
+    for token in definition.split_whitespace() {
+        let token = token.trim_start_matches('+');
+        let (key, value) = token.split_once('=').unwrap_or((token, ""));
+        if matches!(key, "no_defs" | "type" | "wktext") {
+            continue;
+        }
+        parameters.insert(key.to_string(), value.to_string());
+    }
+
+    let expanded = match parameters.get("datum").map(|x| x.as_str()) {
+        Some("WGS84") => Some(("WGS84", "0,0,0")),
+        Some("NAD83") => Some(("GRS80", "0,0,0")),
+        Some("OSGB36") => Some(("airy", "446.448,-125.157,542.06,0.15,0.247,0.842,-20.489")),
+        _ => None,
+    };
+    if let Some((ellps, towgs84)) = expanded {
+        parameters.remove("datum");
+        parameters.insert("ellps".to_string(), ellps.to_string());
+        parameters
+            .entry("towgs84".to_string())
+            .or_insert_with(|| towgs84.to_string());
+    }
+    if !parameters.contains_key("units") && !parameters.contains_key("to_meter") {
+        parameters.insert("units".to_string(), "m".to_string());
+    }
+
+    for (key, value) in parameters.iter_mut() {
+        let numbers: Option<Vec<f64>> = value.split(',').map(|x| x.parse().ok()).collect();
+        if let Some(mut numbers) = numbers {
+            // The last 4 parameters of a datum transformation default to 0
+            if key == "towgs84" && numbers.len() == 3 {
+                numbers.resize(7, 0.0);
+            }
+            *value = numbers
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+        }
+    }
+    parameters
+}
+
+/// How to measure distances, lengths and areas, in meters.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Metric {
+    Haversine,
+    Euclidean { meters_per_unit: f64 },
+}
+
+impl Metric {
+    pub(crate) fn distance(self, pt1: Point<f64>, pt2: Point<f64>) -> f64 {
+        match self {
+            Metric::Haversine => pt1.haversine_distance(&pt2),
+            Metric::Euclidean { meters_per_unit } => pt1.euclidean_distance(&pt2) * meters_per_unit,
+        }
+    }
+
+    pub(crate) fn length(self, line: Line<f64>) -> f64 {
+        match self {
+            Metric::Haversine => line.haversine_length(),
+            Metric::Euclidean { meters_per_unit } => line.euclidean_length() * meters_per_unit,
+        }
+    }
+
+    pub(crate) fn area(self, polygon: &Polygon<f64>) -> f64 {
+        match self {
+            Metric::Haversine => polygon.chamberlain_duquette_unsigned_area(),
+            Metric::Euclidean { meters_per_unit } => {
+                polygon.unsigned_area() * meters_per_unit * meters_per_unit
+            }
+        }
+    }
+}
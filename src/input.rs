@@ -0,0 +1,709 @@
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use arrow_array::RecordBatch;
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use fs_err::File;
+use geojson::{Feature, FeatureCollection, FeatureReader};
+use geozero::geojson::GeoJsonWriter;
+use geozero::wkb::GpkgWkb;
+use geozero::ToJson;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde_json::{Map, Value};
+
+use crate::{Crs, Options};
+
+/// One row of origin/destination data, describing trips between two zones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OdRecord {
+    /// The name of the origin zone
+    pub origin: String,
+    /// The name of the destination zone
+    pub destination: String,
+    /// Every other column, in order. Output properties keep this order.
+    pub columns: Vec<(String, OdValue)>,
+}
+
+/// One value in an `OdRecord`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OdValue {
+    /// A number of trips, like for one mode. `jitter` scales counts down when it splits a record,
+    /// and `disaggregate` treats each one as a mode. The distance column (see
+    /// `Options::distance_key`) is never a count; it's treated as an attribute instead.
+    Count(f64),
+    /// Anything else, like a name or an observed distance. `jitter` copies attributes to the
+    /// output unchanged, and `disaggregate` ignores them.
+    Attribute(Value),
+}
+
+impl OdValue {
+    fn to_csv_field(&self) -> String {
+        match self {
+            OdValue::Count(x) => x.to_string(),
+            OdValue::Attribute(Value::String(x)) => x.clone(),
+            OdValue::Attribute(Value::Null) => String::new(),
+            OdValue::Attribute(x) => x.to_string(),
+        }
+    }
+}
+
+/// One row of a table, with (column, value) pairs in the order of the header.
+pub(crate) type Row = Vec<(String, String)>;
+
+/// A record read from the input. If asked, records from a table also keep the row they came from,
+/// so it can be written out unchanged.
+pub(crate) struct InputRecord {
+    pub record: OdRecord,
+    pub row: Option<Row>,
+}
+
+/// Which columns of a table have special meaning.
+#[derive(Clone)]
+pub(crate) struct Keys {
+    pub origin: String,
+    pub destination: String,
+    pub distance: Option<String>,
+}
+
+impl Keys {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            origin: options.origin_key.clone(),
+            destination: options.destination_key.clone(),
+            distance: options.distance_key.clone(),
+        }
+    }
+
+    /// The header and fields of a record, as they'd appear in a table.
+    pub fn to_csv(&self, record: &OdRecord) -> (Vec<String>, Vec<String>) {
+        let mut header = vec![self.origin.clone(), self.destination.clone()];
+        let mut fields = vec![record.origin.clone(), record.destination.clone()];
+        for (key, value) in &record.columns {
+            header.push(key.clone());
+            fields.push(value.to_csv_field());
+        }
+        (header, fields)
+    }
+}
+
+/// Where to read origin/destination data from: a table in some format, or records already in
+/// memory.
+///
+/// Every value in a table is read as a string first, so zone names that look numeric keep any
+/// leading zeros. Then the origin and destination columns become the record's zones, the distance
+/// column (see `Options::distance_key`) becomes an attribute, and every other column is a count
+/// if it's numeric, or otherwise an attribute.
+pub struct OdInput {
+    /// Identifies the input in error messages
+    name: String,
+    source: OdSource,
+}
+
+enum OdSource {
+    Csv(PathBuf),
+    /// Only set until it's read
+    CsvReader(Option<Box<dyn Read>>),
+    Parquet(PathBuf),
+    GeoJson(PathBuf),
+    /// Only set until it's read
+    Records(Option<Box<dyn Iterator<Item = OdRecord>>>),
+}
+
+impl OdInput {
+    /// Detects the format from the extension: `.parquet` for Parquet, `.geojson` or `.json` for
+    /// GeoJSON, and CSV otherwise. `-` means CSV from stdin.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Self::csv_reader(std::io::stdin());
+        }
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+        match extension.as_deref() {
+            Some("parquet") => Self::parquet(path),
+            Some("geojson") | Some("json") => Self::geojson(path),
+            _ => Self::csv(path),
+        }
+    }
+
+    /// A CSV file with a header row
+    pub fn csv<P: AsRef<Path>>(path: P) -> Self {
+        Self::new_file(path.as_ref(), OdSource::Csv)
+    }
+
+    /// CSV data with a header row, from any source, like stdin. The rows can only be read once, so
+    /// with `RetryFallback::Error`, each row is checked as it's processed, after trips for earlier
+    /// rows may already have been produced.
+    pub fn csv_reader<R: Read + 'static>(reader: R) -> Self {
+        Self {
+            name: "The OD input".to_string(),
+            source: OdSource::CsvReader(Some(Box::new(reader))),
+        }
+    }
+
+    /// A Parquet file with one row per OD pair
+    pub fn parquet<P: AsRef<Path>>(path: P) -> Self {
+        Self::new_file(path.as_ref(), OdSource::Parquet)
+    }
+
+    /// A GeoJSON file with one feature per OD pair. Only the properties are used; features
+    /// usually don't have any geometry.
+    pub fn geojson<P: AsRef<Path>>(path: P) -> Self {
+        Self::new_file(path.as_ref(), OdSource::GeoJson)
+    }
+
+    /// Records already in memory, or produced lazily. They're only read as the output is consumed,
+    /// unless `Integerisation::TruncateReplicateSample` needs every count upfront. Like with
+    /// `csv_reader`, they can only be checked for `RetryFallback::Error` row by row.
+    pub fn records<I>(records: I) -> Self
+    where
+        I: IntoIterator<Item = OdRecord>,
+        I::IntoIter: 'static,
+    {
+        Self {
+            name: "The OD records".to_string(),
+            source: OdSource::Records(Some(Box::new(records.into_iter()))),
+        }
+    }
+
+    fn new_file(path: &Path, source: fn(PathBuf) -> OdSource) -> Self {
+        Self {
+            name: path.display().to_string(),
+            source: source(path.to_path_buf()),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reads every record, consuming the input, so the records can outlive it. With `keep_rows`,
+    /// records from a table keep their original row.
+    pub(crate) fn into_records(
+        mut self,
+        keys: Keys,
+        keep_rows: bool,
+    ) -> Result<Box<dyn Iterator<Item = Result<InputRecord>>>> {
+        if let OdSource::Records(ref mut records) = self.source {
+            if let Some(records) = records.take() {
+                let distance = keys.distance;
+                return Ok(Box::new(records.map(move |mut record| {
+                    // A distance applies to every trip, so it's not a count, even if it was given
+                    // as one
+                    for (key, value) in &mut record.columns {
+                        if let OdValue::Count(x) = value {
+                            if Some(key.as_str()) == distance.as_deref() {
+                                *value = OdValue::Attribute(json_number(*x));
+                            }
+                        }
+                    }
+                    Ok(InputRecord { record, row: None })
+                })));
+            } else {
+                bail!("{} can only be read once", self.name);
+            }
+        }
+        let rows = self.rows()?;
+        let name = self.name;
+        Ok(Box::new(rows.map(move |row| {
+            let row = row?;
+            let kept = keep_rows.then(|| row.clone());
+            Ok(InputRecord {
+                record: row_to_record(row, &name, &keys)?,
+                row: kept,
+            })
+        })))
+    }
+
+    /// Reads every record from a file, without consuming the input, so it can be checked before
+    /// any output is produced. Readers like stdin and in-memory records can only be read once, so
+    /// this returns `None` for them.
+    pub(crate) fn reread_records(
+        &self,
+        keys: &Keys,
+    ) -> Result<Option<Box<dyn Iterator<Item = Result<OdRecord>>>>> {
+        let rows = if let Some(rows) = self.file_rows()? {
+            rows
+        } else {
+            return Ok(None);
+        };
+        let name = self.name.clone();
+        let keys = keys.clone();
+        Ok(Some(Box::new(
+            rows.map(move |row| row_to_record(row?, &name, &keys)),
+        )))
+    }
+
+    /// Reads rows from a table. In-memory records are handled separately.
+    fn rows(&mut self) -> Result<Box<dyn Iterator<Item = Result<Row>>>> {
+        if let OdSource::CsvReader(ref mut reader) = self.source {
+            if let Some(reader) = reader.take() {
+                return Ok(Box::new(read_csv_rows(reader)?));
+            } else {
+                bail!("{} can only be read once", self.name);
+            }
+        }
+        Ok(self.file_rows()?.unwrap())
+    }
+
+    /// Reads rows from a file, or returns `None` if the input isn't a file.
+    fn file_rows(&self) -> Result<Option<Box<dyn Iterator<Item = Result<Row>>>>> {
+        Ok(Some(match self.source {
+            OdSource::Csv(ref path) => Box::new(read_csv_rows(File::open(path)?)?),
+            OdSource::Parquet(ref path) => read_parquet_rows(path)?,
+            OdSource::GeoJson(ref path) => Box::new(read_geojson_rows(path)?),
+            OdSource::CsvReader(_) | OdSource::Records(_) => return Ok(None),
+        }))
+    }
+}
+
+impl From<Vec<OdRecord>> for OdInput {
+    fn from(records: Vec<OdRecord>) -> Self {
+        Self::records(records)
+    }
+}
+
+impl From<&str> for OdInput {
+    fn from(path: &str) -> Self {
+        Self::from_path(path)
+    }
+}
+
+impl From<String> for OdInput {
+    fn from(path: String) -> Self {
+        Self::from_path(path)
+    }
+}
+
+impl From<&Path> for OdInput {
+    fn from(path: &Path) -> Self {
+        Self::from_path(path)
+    }
+}
+
+impl From<PathBuf> for OdInput {
+    fn from(path: PathBuf) -> Self {
+        Self::from_path(path)
+    }
+}
+
+fn read_csv_rows<R: Read>(reader: R) -> Result<impl Iterator<Item = Result<Row>>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    // It's tempting to deserialize directly into a serde_json::Map<String, Value> and auto-detect
+    // strings and numbers. But sadly, some input data has zone names that look numeric, and even
+    // contain leading zeros, which'll be lost. So first just grab raw strings
+    Ok(reader.into_records().map(move |rec| {
+        let rec = rec?;
+        Ok(headers
+            .iter()
+            .zip(rec.iter())
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
+    }))
+}
+
+fn read_geojson_rows(path: &Path) -> Result<impl Iterator<Item = Result<Row>>> {
+    Ok(read_features(&path.to_string_lossy())?.map(|feature| {
+        Ok(feature?
+            .properties
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, property_to_string(value)))
+            .collect())
+    }))
+}
+
+/// Formats a property as if it was read from a CSV file, so it's interpreted the same way.
+fn property_to_string(value: Value) -> String {
+    match value {
+        Value::String(x) => x,
+        Value::Null => String::new(),
+        x => x.to_string(),
+    }
+}
+
+fn read_parquet_rows(path: &Path) -> Result<Box<dyn Iterator<Item = Result<Row>>>> {
+    let (file, _) = File::open(path)?.into_parts();
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    // Read one batch at a time
+    Ok(Box::new(reader.flat_map(|batch| {
+        match batch.map_err(anyhow::Error::from).and_then(batch_rows) {
+            Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        }
+    })))
+}
+
+/// Formats every value in a batch as a string. Nulls become empty strings.
+fn batch_rows(batch: RecordBatch) -> Result<Vec<Row>> {
+    let schema = batch.schema();
+    let options = FormatOptions::default();
+    let formatters = batch
+        .columns()
+        .iter()
+        .map(|array| ArrayFormatter::try_new(array.as_ref(), &options))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((0..batch.num_rows())
+        .map(|idx| {
+            schema
+                .fields()
+                .iter()
+                .zip(&formatters)
+                .map(|(field, formatter)| (field.name().clone(), formatter.value(idx).to_string()))
+                .collect()
+        })
+        .collect())
+}
+
+fn row_to_record(row: Row, name: &str, keys: &Keys) -> Result<OdRecord> {
+    let mut origin = None;
+    let mut destination = None;
+    let mut columns = Vec::new();
+    for (key, value) in row {
+        // Never treat the origin/destination key as numeric
+        if key == keys.origin || key == keys.destination {
+            if key == keys.origin {
+                origin = Some(value.clone());
+            }
+            if key == keys.destination {
+                destination = Some(value);
+            }
+        } else {
+            let value = parse_value(&key, value, name, keys)?;
+            columns.push((key, value));
+        }
+    }
+
+    let origin = if let Some(id) = origin {
+        id
+    } else {
+        bail!(
+            "{} doesn't have a {} column; set origin_key properly",
+            name,
+            keys.origin
+        );
+    };
+    let destination = if let Some(id) = destination {
+        id
+    } else {
+        bail!(
+            "{} doesn't have a {} column; set destination_key properly",
+            name,
+            keys.destination
+        );
+    };
+    Ok(OdRecord {
+        origin,
+        destination,
+        columns,
+    })
+}
+
+/// Decides if a column other than the origin or destination is a count or an attribute.
+fn parse_value(key: &str, value: String, name: &str, keys: &Keys) -> Result<OdValue> {
+    if Some(key) == keys.distance.as_deref() {
+        // A distance applies to every trip, so it's not a count
+        let value = if value.is_empty() {
+            Value::Null
+        } else if let Ok(x) = value.parse::<f64>() {
+            json_number(x)
+        } else {
+            bail!("{name} has a non-numeric {key} value {value}; set distance_key properly");
+        };
+        Ok(OdValue::Attribute(value))
+    } else if let Ok(x) = value.parse::<f64>() {
+        Ok(OdValue::Count(x))
+    } else {
+        Ok(OdValue::Attribute(Value::String(value)))
+    }
+}
+
+/// Interprets the properties of a feature, like the output of `jitter`, the same way as columns
+/// of OD data. The origin and destination are always attributes, and they don't have to be
+/// present.
+pub(crate) fn feature_columns(
+    feature: &Feature,
+    name: &str,
+    keys: &Keys,
+) -> Result<Vec<(String, OdValue)>> {
+    let mut columns = Vec::new();
+    for (key, value) in feature.properties.iter().flatten() {
+        let value = if *key == keys.origin || *key == keys.destination {
+            OdValue::Attribute(value.clone())
+        } else {
+            parse_value(key, property_to_string(value.clone()), name, keys)?
+        };
+        columns.push((key.clone(), value));
+    }
+    Ok(columns)
+}
+
+/// Reads every feature from a file. The format is detected from the extension: `.fgb` for
+/// FlatGeobuf, `.shp` for an ESRI Shapefile (with its `.dbf` alongside), `.gpkg` for a GeoPackage
+/// with exactly one feature table, and GeoJSON for anything else. GeoJSON is streamed; other
+/// formats are read into memory first.
+pub fn read_features(path: &str) -> Result<Box<dyn Iterator<Item = Result<Feature>>>> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase());
+    let features = match extension.as_deref() {
+        Some("fgb") => read_flatgeobuf(path)?,
+        Some("shp") => read_shapefile(path)?,
+        Some("gpkg") => read_geopackage(path)?,
+        _ => {
+            let reader = FeatureReader::from_reader(BufReader::new(File::open(path)?));
+            // geojson's iterator panics if it's called again after finishing
+            return Ok(Box::new(
+                reader
+                    .features()
+                    .fuse()
+                    .map(|feature| feature.map_err(anyhow::Error::from)),
+            ));
+        }
+    };
+    Ok(Box::new(features.into_iter().map(Ok)))
+}
+
+/// Reads the coordinate reference system that a file in any format `read_features` supports
+/// declares, if any. GeoJSON files are checked for the `crs` member from the 2008 specification.
+/// Shapefiles are checked for an EPSG code or the British National Grid in the `.prj` file.
+/// GeoTIFFs, like `load_geotiff` reads, are checked for an EPSG code in their GeoKeys. It's an
+/// error if a file declares a CRS that can't be identified, or an EPSG code that isn't built into
+/// `Crs::from_epsg`, so it isn't mistaken for WGS84.
+pub fn read_crs(path: &str) -> Result<Option<Crs>> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase());
+    match extension.as_deref() {
+        Some("fgb") => {
+            let mut file = BufReader::new(File::open(path)?);
+            let reader = flatgeobuf::FgbReader::open(&mut file)?;
+            if let Some(crs) = reader.header().crs() {
+                if crs.code() > 0 && crs.org().map(|org| org == "EPSG").unwrap_or(true) {
+                    return Ok(Some(declared_epsg(path, crs.code() as u32)?));
+                }
+            }
+            Ok(None)
+        }
+        Some("tif") | Some("tiff") => match crate::raster::read_geotiff_epsg(path)? {
+            Some(code) => Ok(Some(declared_epsg(path, code)?)),
+            None => Ok(None),
+        },
+        Some("shp") => {
+            let prj = std::path::Path::new(path).with_extension("prj");
+            if !prj.exists() {
+                return Ok(None);
+            }
+            let wkt = fs_err::read_to_string(&prj)?;
+            // The last authority belongs to the outermost definition
+            if let Some(code) = wkt
+                .rsplit("AUTHORITY[\"EPSG\",\"")
+                .next()
+                .filter(|_| wkt.contains("AUTHORITY[\"EPSG\""))
+                .and_then(|rest| rest.split('"').next())
+                .and_then(|code| code.parse().ok())
+            {
+                return Ok(Some(declared_epsg(path, code)?));
+            }
+            // ESRI's WKT doesn't include the EPSG code
+            if wkt.starts_with("PROJCS[\"British_National_Grid\"")
+                || wkt.starts_with("PROJCS[\"OSGB_1936_British_National_Grid\"")
+            {
+                return Ok(Some(Crs::from_epsg(27700)?));
+            }
+            if wkt.starts_with("GEOGCS[\"GCS_WGS_1984\"") {
+                return Ok(Some(Crs::wgs84()));
+            }
+            bail!(
+                "Couldn't determine the CRS of {}; pass --crs",
+                prj.display()
+            );
+        }
+        Some("gpkg") => {
+            let conn = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            let (table, _) = geopackage_layer(&conn, path)?;
+            let (organization, code): (String, i64) = conn.query_row(
+                "SELECT s.organization, s.organization_coordsys_id FROM gpkg_geometry_columns g \
+                 JOIN gpkg_spatial_ref_sys s ON g.srs_id = s.srs_id WHERE g.table_name = ?1",
+                [&table],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            // Undefined systems have negative or zero codes
+            if code <= 0 {
+                Ok(None)
+            } else if organization.eq_ignore_ascii_case("EPSG") {
+                Ok(Some(declared_epsg(path, code as u32)?))
+            } else {
+                bail!("Couldn't determine the CRS of {path}, which uses {organization}:{code}; pass --crs");
+            }
+        }
+        _ => {
+            // Only the crs member is kept. The rest of the file, including every feature, is
+            // still read, but it's skipped over without building any values.
+            #[derive(serde::Deserialize)]
+            struct Header {
+                crs: Option<Value>,
+            }
+            let header: Header = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            match header
+                .crs
+                .as_ref()
+                .and_then(|crs| crs.get("properties"))
+                .and_then(|props| props.get("name"))
+                .and_then(|name| name.as_str())
+            {
+                Some(name) => Ok(Some(Crs::parse(name)?)),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+/// Looks up an EPSG code that a file declares.
+fn declared_epsg(path: &str, code: u32) -> Result<Crs> {
+    match Crs::from_epsg(code) {
+        Ok(crs) => Ok(crs),
+        Err(err) => bail!("Couldn't determine the CRS of {path}; pass --crs ({err})"),
+    }
+}
+
+fn read_flatgeobuf(path: &str) -> Result<Vec<Feature>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut reader = flatgeobuf::FgbReader::open(&mut file)?.select_all()?;
+    // Let geozero convert everything, with property types, to GeoJSON
+    let mut json = Vec::new();
+    reader.process_features(&mut GeoJsonWriter::new(&mut json))?;
+    let collection: FeatureCollection = serde_json::from_slice(&json)?;
+    Ok(collection.features)
+}
+
+fn read_shapefile(path: &str) -> Result<Vec<Feature>> {
+    let mut reader = shapefile::Reader::from_path(path)?;
+    let mut features = Vec::new();
+    for result in reader.iter_shapes_and_records() {
+        let (shape, record) = result?;
+        let geometry = match shape {
+            shapefile::Shape::NullShape => None,
+            shape => {
+                let geometry: geo_types::Geometry<f64> = match shape.try_into() {
+                    Ok(geometry) => geometry,
+                    Err(err) => bail!("Can't read a shape from {path}: {err}"),
+                };
+                Some(geojson::Geometry::from(&geometry))
+            }
+        };
+
+        // The fields come from a HashMap, so sort them
+        let mut fields: Vec<(String, shapefile::dbase::FieldValue)> = record.into_iter().collect();
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut properties = Map::new();
+        for (key, value) in fields {
+            use shapefile::dbase::FieldValue;
+            let value = match value {
+                FieldValue::Character(Some(x)) | FieldValue::Memo(x) => Value::from(x),
+                FieldValue::Numeric(Some(x)) | FieldValue::Currency(x) | FieldValue::Double(x) => {
+                    json_number(x)
+                }
+                FieldValue::Float(Some(x)) => json_number(x.into()),
+                FieldValue::Integer(x) => Value::from(x),
+                FieldValue::Logical(Some(x)) => Value::from(x),
+                FieldValue::Date(Some(x)) => {
+                    Value::from(format!("{:04}-{:02}-{:02}", x.year(), x.month(), x.day()))
+                }
+                _ => Value::Null,
+            };
+            properties.insert(key, value);
+        }
+
+        features.push(Feature {
+            bbox: None,
+            geometry,
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        });
+    }
+    Ok(features)
+}
+
+fn read_geopackage(path: &str) -> Result<Vec<Feature>> {
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let (table, geometry_column) = geopackage_layer(&conn, path)?;
+
+    let mut statement = conn.prepare(&format!("SELECT * FROM \"{table}\""))?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect();
+    let mut rows = statement.query([])?;
+    let mut features = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut geometry = None;
+        let mut properties = Map::new();
+        for (idx, key) in columns.iter().enumerate() {
+            use rusqlite::types::ValueRef;
+            let value = row.get_ref(idx)?;
+            if *key == geometry_column {
+                if let ValueRef::Blob(blob) = value {
+                    let json = GpkgWkb(blob.to_vec()).to_json()?;
+                    geometry = Some(serde_json::from_str(&json)?);
+                }
+                continue;
+            }
+            let value = match value {
+                ValueRef::Null | ValueRef::Blob(_) => Value::Null,
+                ValueRef::Integer(x) => Value::from(x),
+                ValueRef::Real(x) => json_number(x),
+                ValueRef::Text(x) => Value::from(String::from_utf8_lossy(x).into_owned()),
+            };
+            properties.insert(key.clone(), value);
+        }
+        features.push(Feature {
+            bbox: None,
+            geometry,
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        });
+    }
+    Ok(features)
+}
+
+/// Finds the only feature table in a GeoPackage, and its geometry column.
+fn geopackage_layer(conn: &rusqlite::Connection, path: &str) -> Result<(String, String)> {
+    let layers = conn
+        .prepare(
+            "SELECT c.table_name, g.column_name FROM gpkg_contents c JOIN gpkg_geometry_columns g \
+             ON c.table_name = g.table_name WHERE c.data_type = 'features'",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+    if layers.len() != 1 {
+        bail!(
+            "{path} needs exactly one feature table, but has {:?}",
+            layers
+                .into_iter()
+                .map(|(table, _)| table)
+                .collect::<Vec<_>>()
+        );
+    }
+    Ok(layers.into_iter().next().unwrap())
+}
+
+/// NaN and infinity can't be represented in JSON, so they become null.
+pub(crate) fn json_number(x: f64) -> Value {
+    serde_json::Number::from_f64(x)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
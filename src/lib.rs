@@ -3,29 +3,48 @@
 //!
 //! TODO: Motivate and explain with a full example.
 
+mod crs;
+mod input;
+mod output;
+mod overline;
+mod raster;
+mod route;
 mod scrape;
 #[cfg(test)]
 mod tests;
 
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::io::BufReader;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
 
 use anyhow::{bail, Result};
 use fs_err::File;
+use geo::algorithm::area::Area;
 use geo::algorithm::bounding_rect::BoundingRect;
 use geo::algorithm::contains::Contains;
-use geo::algorithm::haversine_distance::HaversineDistance;
-use geo_types::{LineString, MultiPolygon, Point, Rect};
-use geojson::{Feature, FeatureReader};
+use geo::algorithm::coords_iter::CoordsIter;
+use geo::algorithm::line_intersection::{line_intersection, LineIntersection};
+use geo::algorithm::lines_iter::LinesIter;
+use geo_types::{Coord, Line, LineString, MultiPolygon, Point, Polygon, Rect};
+use geojson::Feature;
 use ordered_float::NotNan;
-use rand::prelude::SliceRandom;
-use rand::rngs::StdRng;
-use rand::Rng;
-use rstar::{RTree, RTreeObject, AABB};
+use rand::distributions::Distribution;
+use rand::{Rng, SeedableRng};
+use rand_distr::{WeightedAliasIndex, WeightedError};
+use rstar::{Envelope, RTree, RTreeObject, AABB};
 use serde_json::{Map, Value};
 
-pub use self::scrape::scrape_points;
+pub use self::crs::Crs;
+use self::crs::Metric;
+pub use self::input::{read_crs, read_features, OdInput, OdRecord, OdValue};
+use self::input::{InputRecord, Keys, Row};
+pub use self::output::{
+    CsvGeometry, CsvWriter, FlatGeobufWriter, GeoJsonSeqWriter, GeoJsonWriter, GeoParquetWriter,
+    Metadata,
+};
+pub use self::overline::{overline, OverlineOptions};
+pub use self::raster::{load_geotiff, Raster};
+pub use self::route::RoadNetwork;
+pub use self::scrape::{scrape_lines, scrape_points, scrape_polygons};
 
 pub struct Options {
     /// How to pick points from origin zones
@@ -36,12 +55,207 @@ pub struct Options {
     pub origin_key: String,
     /// Which column in the OD row specifies the zone where trips ends?
     pub destination_key: String,
-    /// Guarantee that jittered points are at least this distance apart.
+    /// Guarantee that jittered points are at least this distance apart, unless `retry_fallback`
+    /// says otherwise.
     pub min_distance_meters: f64,
+    /// If specified, guarantee that jittered points are at most this distance apart, unless
+    /// `retry_fallback` says otherwise.
+    pub max_distance_meters: Option<f64>,
+    /// Overrides `max_distance_meters` for some mode columns in `disaggregate`, so walking trips
+    /// can be shorter than driving trips, for example. `jitter` ignores this.
+    pub max_distance_meters_per_mode: HashMap<String, f64>,
     /// Prevent duplicate (origin, destination) pairs from appearing in the output. This may
     /// increase memory and runtime requirements. Note the duplication uses the floating point
     /// precision of the input data, and only consider geometry (not any properties).
     pub deduplicate_pairs: bool,
+    /// How `disaggregate` turns fractional trip counts into a whole number of trips. `jitter`
+    /// ignores this.
+    pub integerisation: Integerisation,
+    /// What to do with OD rows referencing an origin or destination zone that doesn't exist.
+    pub unknown_zones: UnknownZones,
+    /// How many times to sample an origin and destination point satisfying the distance
+    /// constraints (and `deduplicate_pairs`) before giving up on one trip.
+    pub max_attempts: usize,
+    /// What to do with a trip after `max_attempts` fail.
+    pub retry_fallback: RetryFallback,
+    /// If specified, this column in the OD row has the observed mean distance of its trips, in
+    /// meters. Trips are sampled to approximately match it, by picking the pair of points with the
+    /// closest distance out of `distance_candidates` pairs satisfying the other constraints. Rows
+    /// with an empty value are sampled normally, and a non-numeric or negative value is an error.
+    /// The target isn't a constraint: if no pair comes close to it, like when it's longer than
+    /// the zones are apart, the closest pairs found are used, and `retry_fallback` doesn't apply.
+    /// This column isn't scaled by `jitter` or treated as a mode by `disaggregate`.
+    pub distance_key: Option<String>,
+    /// How many pairs of points to consider per trip when matching `distance_key`. More
+    /// candidates match the distances more closely, but take longer.
+    pub distance_candidates: usize,
+    /// The coordinate reference system of the zones and subpoints. This determines how distances
+    /// are measured.
+    pub crs: Crs,
+    /// Transform the output from `crs` to WGS84.
+    pub reproject_to_wgs84: bool,
+}
+
+impl Default for Options {
+    /// Matches the defaults of the command line tool: random points in each zone, `geo_code1` and
+    /// `geo_code2` as the zone columns, at least 1 meter between points, and WGS84 coordinates.
+    fn default() -> Self {
+        Self {
+            subsample_origin: Subsample::RandomPoints,
+            subsample_destination: Subsample::RandomPoints,
+            origin_key: "geo_code1".to_string(),
+            destination_key: "geo_code2".to_string(),
+            min_distance_meters: 1.0,
+            max_distance_meters: None,
+            max_distance_meters_per_mode: HashMap::new(),
+            deduplicate_pairs: false,
+            integerisation: Integerisation::Truncate,
+            unknown_zones: UnknownZones::Fail,
+            max_attempts: 1000,
+            retry_fallback: RetryFallback::Error,
+            distance_key: None,
+            distance_candidates: 20,
+            crs: Crs::wgs84(),
+            reproject_to_wgs84: false,
+        }
+    }
+}
+
+/// Specifies what happens when no pair of points satisfying the constraints in `Options` is found
+/// for a trip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryFallback {
+    /// Stop with an error. The zones of every row are also checked, based on the bounding boxes of
+    /// the zones or subpoints, to fail quickly when the constraints are impossible. Files are
+    /// checked before any output is produced, which reads them twice. Readers like stdin and
+    /// in-memory records can only be read once, so their rows are checked as they're processed,
+    /// and trips for earlier rows may already have been produced.
+    Error,
+    /// Use the pair that came closest to satisfying the distance constraints. If every attempt
+    /// was a duplicate pair, the trip is dropped.
+    AcceptClosest,
+    /// Drop the trip from the output.
+    Drop,
+}
+
+/// The zones that origins and destinations are named by. Usually this is one map for both, but
+/// origins and destinations can use different zone systems, like residential zones and school
+/// catchments, even if the names overlap.
+///
+/// Either end can also refer to specific places, like schools or hospitals, by ID. Trips to or
+/// from an ID in `origin_points` or `destination_points` start or end exactly at that point, and
+/// aren't jittered. IDs not found there are looked up in the zones as usual.
+#[derive(Clone, Copy)]
+pub struct Zones<'a> {
+    pub origins: &'a HashMap<String, MultiPolygon<f64>>,
+    pub destinations: &'a HashMap<String, MultiPolygon<f64>>,
+    pub origin_points: Option<&'a HashMap<String, Point<f64>>>,
+    pub destination_points: Option<&'a HashMap<String, Point<f64>>>,
+}
+
+impl<'a> Zones<'a> {
+    pub fn new(
+        origins: &'a HashMap<String, MultiPolygon<f64>>,
+        destinations: &'a HashMap<String, MultiPolygon<f64>>,
+    ) -> Self {
+        Self {
+            origins,
+            destinations,
+            origin_points: None,
+            destination_points: None,
+        }
+    }
+
+    fn origin_point(&self, id: &str) -> Option<Point<f64>> {
+        self.origin_points
+            .and_then(|points| points.get(id))
+            .copied()
+    }
+
+    fn destination_point(&self, id: &str) -> Option<Point<f64>> {
+        self.destination_points
+            .and_then(|points| points.get(id))
+            .copied()
+    }
+
+    fn has_origin(&self, id: &str) -> bool {
+        self.origin_point(id).is_some() || self.origins.contains_key(id)
+    }
+
+    fn has_destination(&self, id: &str) -> bool {
+        self.destination_point(id).is_some() || self.destinations.contains_key(id)
+    }
+}
+
+impl<'a> From<&'a HashMap<String, MultiPolygon<f64>>> for Zones<'a> {
+    /// Uses the same zones for origins and destinations.
+    fn from(zones: &'a HashMap<String, MultiPolygon<f64>>) -> Self {
+        Self::new(zones, zones)
+    }
+}
+
+/// Specifies what happens to OD rows whose origin or destination zone doesn't exist.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnknownZones {
+    /// Stop with an error.
+    Fail,
+    /// Skip the row. A warning is printed the first time each unknown zone is seen.
+    Skip,
+    /// Skip the row like `Skip`, and also write it unchanged to a CSV file at this path.
+    Reject(PathBuf),
+}
+
+/// Describes input rows and trips that `jitter` or `disaggregate` skipped.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// How many input rows were skipped because they reference unknown zones
+    pub rows_skipped: usize,
+    /// The number of trips in the skipped rows. For `jitter`, this is the value of
+    /// `disaggregation_key`. For `disaggregate`, it's the sum of all mode columns.
+    pub trips_skipped: f64,
+    /// Every unknown zone, with the number of trips in skipped rows referencing it
+    pub unknown_zones: BTreeMap<String, f64>,
+    /// How many output trips were dropped, because no pair of points satisfying the constraints
+    /// was found within `max_attempts`
+    pub trips_dropped: usize,
+    /// How many output trips don't satisfy the distance constraints, because of
+    /// `RetryFallback::AcceptClosest`
+    pub trips_violating_constraints: usize,
+    /// How well trips match the target distances, if `distance_key` is specified
+    pub distances: Option<DistanceSummary>,
+}
+
+/// Compares the distance of output trips to the target from `distance_key`. Only trips with a
+/// target distance are included.
+#[derive(Debug, Default)]
+pub struct DistanceSummary {
+    /// How many output trips have a target distance
+    pub trips: usize,
+    /// The mean target distance of those trips, in meters
+    pub mean_target_meters: f64,
+    /// The mean distance actually achieved, in meters
+    pub mean_achieved_meters: f64,
+    /// The mean absolute difference between the target and achieved distance of each trip, in
+    /// meters
+    pub mean_absolute_error_meters: f64,
+}
+
+/// Specifies how fractional trip counts, like the output of a model, become a whole number of
+/// trips.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integerisation {
+    /// Round down, so 0.9 trips becomes 0 trips.
+    Truncate,
+    /// Round to the nearest whole number, with halves rounding up.
+    Round,
+    /// Round up with probability equal to the fractional part, so 0.3 trips becomes 1 trip 30% of
+    /// the time, and 0 trips otherwise. Totals are preserved on average.
+    Stochastic,
+    /// Truncate, replicate, sample: round every count down, then share out the trips lost for
+    /// each mode by picking rows without replacement, weighted by their fractional parts. Each
+    /// count is rounded either up or down, and the total for each mode matches the input, rounded
+    /// to the nearest whole trip. This reads all input rows into memory first.
+    TruncateReplicateSample,
 }
 
 /// Specifies how specific points should be generated within a zone.
@@ -56,6 +270,29 @@ pub enum Subsample {
     /// than one zone, it'll be assigned to any of those zones arbitrarily. (This means the input
     /// zones overlap.)
     WeightedPoints(Vec<WeightedPoint>),
+    /// Sample from positions along LineStrings, such as a road network. Each LineString is
+    /// treated as a continuous line, so every position along its length is equally likely,
+    /// scaled by the relative weight of the LineString.
+    ///
+    /// LineStrings crossing a zone's boundary are clipped to the zone first, so only the length
+    /// inside the zone counts.
+    WeightedLines(Vec<WeightedLineString>),
+    /// Pick points uniformly at random inside polygons, such as building footprints. Each polygon
+    /// is chosen with probability proportional to its area multiplied by its relative weight.
+    ///
+    /// For polygons crossing a zone's boundary, points landing outside the zone are rejected and
+    /// sampled again, so only the area inside the zone counts. Polygons are only used if one of
+    /// their vertices is inside the zone, or they contain one of the zone's vertices.
+    WeightedPolygons(Vec<WeightedPolygon>),
+    /// Pick a raster cell, with probability proportional to its value, then a point uniformly at
+    /// random within that cell. This is useful for gridded population data.
+    ///
+    /// Cells crossing a zone's boundary are clipped to the zone first, so their value is scaled by
+    /// the fraction of the cell's area inside the zone, and points are only sampled from that
+    /// part. Cells that are missing data or have a value that isn't positive are never picked.
+    /// Points in a zone without any positive cells, like a small zone in a coarse grid of zeros,
+    /// are sampled uniformly within the zone instead.
+    WeightedRaster(Raster),
 }
 
 /// A point with an associated relative weight. Higher weights are more likely to be sampled.
@@ -73,316 +310,1333 @@ impl RTreeObject for WeightedPoint {
     }
 }
 
+/// A LineString with an associated relative weight. The probability of sampling some position
+/// along the line is proportional to its length multiplied by this weight.
+#[derive(Clone)]
+pub struct WeightedLineString {
+    pub line_string: LineString<f64>,
+    pub weight: f64,
+}
+
+impl RTreeObject for WeightedLineString {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        match self.line_string.bounding_rect() {
+            Some(bounds) => AABB::from_corners(
+                [bounds.min().x, bounds.min().y],
+                [bounds.max().x, bounds.max().y],
+            ),
+            None => AABB::new_empty(),
+        }
+    }
+}
+
+/// A polygon with an associated relative weight. The probability of sampling a point inside the
+/// polygon is proportional to its area multiplied by this weight.
+#[derive(Clone)]
+pub struct WeightedPolygon {
+    pub polygon: Polygon<f64>,
+    pub weight: f64,
+}
+
+impl RTreeObject for WeightedPolygon {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        match self.polygon.bounding_rect() {
+            Some(bounds) => AABB::from_corners(
+                [bounds.min().x, bounds.min().y],
+                [bounds.max().x, bounds.max().y],
+            ),
+            None => AABB::new_empty(),
+        }
+    }
+}
+
+/// A `WeightedPolygon` overlapping a zone. The weight already accounts for the area.
+#[derive(Clone)]
+struct OverlappingPolygon {
+    polygon: Polygon<f64>,
+    bounds: Rect<f64>,
+    weight: f64,
+    /// If true, some of the polygon is outside the zone
+    crosses_boundary: bool,
+}
+
+/// A raster cell overlapping a zone. The weight is the cell's value, scaled by the fraction of the
+/// cell's area inside the zone.
+#[derive(Clone)]
+struct RasterCell {
+    /// Covers the part of the cell inside the zone
+    bounds: Rect<f64>,
+    weight: f64,
+    /// For cells crossing the zone's boundary, the part of the zone inside the cell
+    clipped: Option<MultiPolygon<f64>>,
+}
+
+/// One straight segment of a `WeightedLineString` inside a zone. The weight already accounts for
+/// the segment's length.
+#[derive(Clone)]
+struct WeightedLine {
+    line: Line<f64>,
+    weight: f64,
+}
+
 /// This method transforms aggregate origin/destination pairs into a disaggregated form, by
 /// sampling specific points from the zone.
 ///
-/// The input is a CSV file, with each row representing trips between an origin and destination,
-/// expressed as a named zone. The columns in the CSV file can break down the number of trips by
-/// different modes (like walking, cycling, etc).
+/// The input is a table, like a CSV file, or `OdRecord`s already in memory (see `OdInput`), with
+/// each row representing trips between an origin and destination, expressed as a named zone. The
+/// counts can break down the number of trips by different modes (like walking, cycling, etc).
 ///
 /// Each input row is repeated some number of times, based on `disaggregation_threshold`. If the
 /// row originally represents 100 trips and `disaggregation_threshold` is 5, then the row will be
 /// repeated 20 times. Each time, the origin and destination will be transformed from the entire
 /// zone to a specific point within the zone, determined using the specified `Subsample`.
 ///
-/// The output LineStrings are provided by callback.
+/// The output LineStrings are provided by callback, or as an iterator by `jitter_trips`.
 ///
-/// Note this assumes assumes all input is in the WGS84 coordinate system, and uses the Haversine
-/// formula to calculate distances.
+/// Distances are calculated using the Haversine formula if `options.crs` is geographic, like WGS84,
+/// or in a straight line if it's projected.
 ///
 /// # Arguments
 ///
+/// * `zones` - The zones that the OD rows refer to by name. Pass one map for both origins and
+///   destinations, or `Zones` to use different zone systems for each.
 /// * `disaggregation_threshold` - What's the maximum number of trips per output OD row that's
 ///   allowed? If an input OD row contains less than this, it will appear in the output without
 ///   transformation. Otherwise, the input row is repeated until the sum matches the original value,
 ///   but each output row obeys this maximum.
 /// * `disaggregation_key` - Which column in the OD row specifies the total number of trips to
 ///   disaggregate?
-pub fn jitter<P: AsRef<Path>, F: FnMut(Feature) -> Result<()>>(
-    csv_path: P,
-    zones: &HashMap<String, MultiPolygon<f64>>,
+/// * `rng` - Seeds the random number generators used for every row. For output that's
+///   reproducible across versions of this crate's dependencies, use an algorithm with a portable
+///   and stable output, like `rand_chacha::ChaCha8Rng` or `rand_pcg::Pcg64`, instead of
+///   `StdRng`.
+pub fn jitter<
+    'a,
+    I: Into<OdInput>,
+    Z: Into<Zones<'a>>,
+    R: Rng + SeedableRng + Send + 'a,
+    F: FnMut(Feature) -> Result<()>,
+>(
+    input: I,
+    zones: Z,
     disaggregation_threshold: usize,
     disaggregation_key: String,
-    rng: &mut StdRng,
+    rng: &mut R,
     options: Options,
     mut output: F,
-) -> Result<()> {
+) -> Result<Summary> {
+    let mut trips = jitter_trips(
+        input,
+        zones,
+        disaggregation_threshold,
+        disaggregation_key,
+        rng,
+        options,
+    )?;
+    for trip in &mut trips {
+        output(trip?.into())?;
+    }
+    trips.finish()
+}
+
+/// Like `jitter`, but returns the output as an iterator of `JitteredTrip`s, instead of passing
+/// features to a callback. Input rows are only read as the iterator is consumed.
+pub fn jitter_trips<'a, I: Into<OdInput>, Z: Into<Zones<'a>>, R: Rng + SeedableRng + Send + 'a>(
+    input: I,
+    zones: Z,
+    disaggregation_threshold: usize,
+    disaggregation_key: String,
+    rng: &mut R,
+    options: Options,
+) -> Result<JitteredTrips<'a>> {
     // TODO Don't allow disaggregation_threshold to be 0
-    let csv_path = csv_path.as_ref();
+    let input = input.into();
+    let zones = zones.into();
+    let input_name = input.name().to_string();
+    let keys = Keys::new(&options);
 
-    let points_per_origin_zone: Option<BTreeMap<String, Vec<WeightedPoint>>> =
-        if let Subsample::WeightedPoints(points) = options.subsample_origin {
-            Some(points_per_polygon(points, zones))
-        } else {
-            None
-        };
-    let points_per_destination_zone: Option<BTreeMap<String, Vec<WeightedPoint>>> =
-        if let Subsample::WeightedPoints(points) = options.subsample_destination {
-            Some(points_per_polygon(points, zones))
-        } else {
-            None
-        };
+    let constraints = PairConstraints::new(&options);
+    let metric = options.crs.metric();
+    let points_per_origin_zone =
+        SubpointsPerZone::new(options.subsample_origin, zones.origins, metric)?;
+    let points_per_destination_zone =
+        SubpointsPerZone::new(options.subsample_destination, zones.destinations, metric)?;
+    let reproject = options.reproject_to_wgs84.then(|| options.crs.clone());
+    let base_seed: u64 = rng.gen();
 
+    let mut checked_upfront = false;
+    if constraints.needs_feasibility_check() {
+        if let Some(records) = input.reread_records(&keys)? {
+            check_feasibility(
+                records.map(|record| {
+                    let record = record?;
+                    Ok((record.origin, record.destination, vec![constraints]))
+                }),
+                zones,
+                &points_per_origin_zone,
+                &points_per_destination_zone,
+            )?;
+            checked_upfront = true;
+        }
+    }
+
+    let keep_rows = matches!(options.unknown_zones, UnknownZones::Reject(_));
+    let records = input.into_records(keys.clone(), keep_rows)?;
+    let summary = SummaryBuilder::new(&options.unknown_zones, &keys)?;
     let mut seen_pairs: HashSet<ODPair> = HashSet::new();
 
-    println!("Disaggregating OD data");
-    for rec in csv::Reader::from_reader(File::open(csv_path)?).deserialize() {
-        // It's tempting to deserialize directly into a serde_json::Map<String, Value> and
-        // auto-detect strings and numbers. But sadly, some input data has zone names that look
-        // numeric, and even contain leading zeros, which'll be lost. So first just grab raw
-        // strings
-        let string_map: HashMap<String, String> = rec?;
-
-        // How many times will we jitter this one row?
-        let repeat = if let Some(count) = string_map
-            .get(&disaggregation_key)
-            .and_then(|count| count.parse::<f64>().ok())
-        {
-            // If disaggregation_key is 0 for this row, don't scale the counts, but still preserve
-            // the row (and jitter it just once)
-            if count == 0.0 {
+    eprintln!("Disaggregating OD data");
+    let handle_batch = move |batch: Vec<(usize, InputRecord)>,
+                             summary: &mut SummaryBuilder,
+                             trips: &mut VecDeque<JitteredTrip>|
+          -> Result<()> {
+        let process = |row_idx, input: InputRecord| {
+            let InputRecord { record, row } = input;
+            let count = if let Some(count) =
+                record.columns.iter().find_map(|(key, value)| match value {
+                    OdValue::Count(count) if *key == disaggregation_key => Some(*count),
+                    _ => None,
+                }) {
+                count
+            } else {
+                bail!(
+                    "{} doesn't have a {} column or the value isn't numeric; set disaggregation_key properly",
+                    input_name,
+                    disaggregation_key
+                );
+            };
+            // How many times will we jitter this one row? If disaggregation_key is 0 for this row,
+            // don't scale the counts, but still preserve the row (and jitter it just once)
+            let repeat = if count == 0.0 {
                 1.0
             } else {
                 (count / disaggregation_threshold as f64).ceil()
+            };
+
+            let missing = unknown_zones(
+                zones,
+                &record.origin,
+                &record.destination,
+                &options.unknown_zones,
+            )?;
+            if !missing.is_empty() {
+                return Ok(RowOutcome::Skipped(SkippedRow {
+                    record,
+                    row,
+                    missing,
+                    trips: count,
+                }));
             }
-        } else {
-            bail!(
-                "{} doesn't have a {} column or the value isn't numeric; set disaggregation_key properly",
-                csv_path.display(),
-                disaggregation_key
-            );
-        };
 
-        // Transform to a JSON map
-        let mut json_map: Map<String, Value> = Map::new();
-        for (key, value) in string_map {
-            let json_value = if key == options.origin_key || key == options.destination_key {
-                // Never treat the origin/destination key as numeric
-                Value::String(value)
-            } else if let Ok(x) = value.parse::<f64>() {
-                // Scale all of the numeric values. Note the unwrap is safe -- we should never wind
-                // up with NaN or infinity
-                Value::Number(serde_json::Number::from_f64(x / repeat).unwrap())
-            } else {
-                Value::String(value)
-            };
-            json_map.insert(key, json_value);
-        }
+            let constraints = constraints.with_target(target_distance(
+                &record,
+                &input_name,
+                &options.distance_key,
+            )?);
 
-        let origin_id = if let Some(Value::String(id)) = json_map.get(&options.origin_key) {
-            id
-        } else {
-            bail!(
-                "{} doesn't have a {} column; set origin_key properly",
-                csv_path.display(),
-                options.origin_key
+            // Transform to a JSON map
+            let mut json_map: Map<String, Value> = Map::new();
+            json_map.insert(
+                options.origin_key.clone(),
+                Value::String(record.origin.clone()),
             );
-        };
-        let destination_id = if let Some(Value::String(id)) = json_map.get(&options.destination_key)
-        {
-            id
-        } else {
-            bail!(
-                "{} doesn't have a {} column; set destination_key properly",
-                csv_path.display(),
-                options.destination_key
+            json_map.insert(
+                options.destination_key.clone(),
+                Value::String(record.destination.clone()),
             );
-        };
+            for (key, value) in record.columns {
+                let json_value = match value {
+                    // Scale all of the counts. Note the unwrap is safe -- we should never wind up
+                    // with NaN or infinity
+                    OdValue::Count(x) => {
+                        Value::Number(serde_json::Number::from_f64(x / repeat).unwrap())
+                    }
+                    OdValue::Attribute(value) => value,
+                };
+                json_map.insert(key, json_value);
+            }
+            let (origin_id, destination_id) = (record.origin, record.destination);
 
-        let origin_zone = if let Some(zone) = zones.get(origin_id) {
-            zone
-        } else {
-            bail!("Unknown origin zone {origin_id}");
-        };
-        let destination_zone = if let Some(zone) = zones.get(destination_id) {
-            zone
-        } else {
-            bail!("Unknown destination zone {destination_id}");
-        };
-        let origin_sampler = Subsampler::new(&points_per_origin_zone, origin_zone, &origin_id)?;
-        let destination_sampler = Subsampler::new(
-            &points_per_destination_zone,
-            destination_zone,
-            &destination_id,
-        )?;
+            let (origin_sampler, destination_sampler) = samplers_for_row(
+                zones,
+                &points_per_origin_zone,
+                &points_per_destination_zone,
+                &origin_id,
+                &destination_id,
+            )?;
+            if !checked_upfront {
+                constraints.check_feasible(
+                    &origin_sampler,
+                    &destination_sampler,
+                    &origin_id,
+                    &destination_id,
+                )?;
+            }
 
-        if options.deduplicate_pairs {
-            if let (Some(num_origin), Some(num_destination)) = (
-                origin_sampler.num_points(),
-                destination_sampler.num_points(),
-            ) {
-                if repeat as usize > num_origin * num_destination {
-                    bail!("{repeat} unique pairs requested from {origin_id} ({num_origin} subpoints) to {destination_id} ({num_destination} subpoints), but this is impossible");
+            if constraints.deduplicate_pairs {
+                if let (Some(num_origin), Some(num_destination)) = (
+                    origin_sampler.num_points(),
+                    destination_sampler.num_points(),
+                ) {
+                    if repeat as usize > num_origin * num_destination {
+                        bail!("{repeat} unique pairs requested from {origin_id} ({num_origin} subpoints) to {destination_id} ({num_destination} subpoints), but this is impossible");
+                    }
                 }
             }
-        }
 
-        for _ in 0..repeat as usize {
-            loop {
-                let o = origin_sampler.sample(rng);
-                let d = destination_sampler.sample(rng);
-                if o.haversine_distance(&d) >= options.min_distance_meters {
-                    if options.deduplicate_pairs {
-                        let pair = hashify(o, d);
-                        if seen_pairs.contains(&pair) {
-                            continue;
-                        } else {
-                            seen_pairs.insert(pair);
+            let mut rng: R = rng_for_row(base_seed, row_idx);
+            let mut row_pairs: HashSet<ODPair> = HashSet::new();
+            let mut pairs = Vec::new();
+            let mut stats = RowStats::default();
+            for _ in 0..repeat as usize {
+                if let Some((o, d)) = sample_pair(
+                    &origin_sampler,
+                    &destination_sampler,
+                    &constraints,
+                    &mut row_pairs,
+                    &mut rng,
+                ) {
+                    pairs.push((o, d));
+                } else {
+                    stats.no_pair_found(&constraints, &origin_id, &destination_id)?;
+                }
+            }
+
+            Ok(RowOutcome::Jittered(JitteredRow {
+                origin_id,
+                destination_id,
+                properties: json_map,
+                pairs,
+                constraints,
+                row_pairs,
+                rng,
+                stats,
+            }))
+        };
+
+        for outcome in process_batch(batch, process) {
+            let mut row = match outcome? {
+                RowOutcome::Jittered(row) => row,
+                RowOutcome::Skipped(skipped) => {
+                    summary.skip_row(skipped)?;
+                    continue;
+                }
+            };
+            for (o, d) in std::mem::take(&mut row.pairs) {
+                let mut pair = Some((o, d));
+                if row.constraints.deduplicate_pairs && !seen_pairs.insert(hashify(o, d)) {
+                    // A previous row already used this pair. Resample using this row's RNG, so
+                    // the result doesn't depend on how rows were split between threads.
+                    let (origin_sampler, destination_sampler) = samplers_for_row(
+                        zones,
+                        &points_per_origin_zone,
+                        &points_per_destination_zone,
+                        &row.origin_id,
+                        &row.destination_id,
+                    )?;
+                    pair = None;
+                    for _ in 0..row.constraints.max_attempts {
+                        match sample_pair(
+                            &origin_sampler,
+                            &destination_sampler,
+                            &row.constraints,
+                            &mut row.row_pairs,
+                            &mut row.rng,
+                        ) {
+                            Some((o, d)) if seen_pairs.insert(hashify(o, d)) => {
+                                pair = Some((o, d));
+                                break;
+                            }
+                            Some(_) => {}
+                            None => break,
                         }
                     }
-
-                    output(to_geojson(o, d, json_map.clone()))?;
-                    break;
+                    if pair.is_none() {
+                        row.stats.no_pair_found(
+                            &row.constraints,
+                            &row.origin_id,
+                            &row.destination_id,
+                        )?;
+                    }
+                }
+                if let Some((o, d)) = pair {
+                    row.stats.record(&row.constraints, o, d);
+                    trips.push_back(JitteredTrip {
+                        origin: o,
+                        destination: d,
+                        origin_zone: row.origin_id.clone(),
+                        destination_zone: row.destination_id.clone(),
+                        properties: row.properties.clone(),
+                    });
                 }
             }
+            summary.add_stats(&row.stats);
         }
-    }
-    Ok(())
+        Ok(())
+    };
+    Ok(JitteredTrips::new(
+        records,
+        handle_batch,
+        summary,
+        reproject,
+    ))
 }
 
 /// This method transforms aggregate origin/destination pairs into a fully disaggregated form, by
 /// sampling specific points from the zone.
 ///
-/// The input is a CSV file, with each row representing trips between an origin and destination,
-/// expressed as a named zone. All numeric columns in the CSV file are interpreted as a number of
-/// trips by different modes (like walking, cycling, etc).
+/// The input is a table, like a CSV file, or `OdRecord`s already in memory (see `OdInput`), with
+/// each row representing trips between an origin and destination, expressed as a named zone. All
+/// counts are interpreted as a number of trips by different modes (like walking, cycling, etc).
 ///
 /// Each input row is repeated some number of times, based on the counts in each mode column. The
-/// output will have a new `mode` column set to that.
-///
-/// The output LineStrings are provided by callback.
+/// output will have a new `mode` column set to that. Like `jitter`, `zones` can be one map or
+/// separate `Zones` for origins and destinations.
 ///
-/// Note this assumes assumes all input is in the WGS84 coordinate system, and uses the Haversine
-/// formula to calculate distances.
+/// The output LineStrings are provided by callback, or as an iterator by `disaggregate_trips`. See
+/// `jitter` for advice about choosing `rng`.
 ///
-pub fn disaggregate<P: AsRef<Path>, F: FnMut(Feature) -> Result<()>>(
-    csv_path: P,
-    zones: &HashMap<String, MultiPolygon<f64>>,
-    rng: &mut StdRng,
+/// Distances are calculated using the Haversine formula if `options.crs` is geographic, like WGS84,
+/// or in a straight line if it's projected.
+pub fn disaggregate<
+    'a,
+    I: Into<OdInput>,
+    Z: Into<Zones<'a>>,
+    R: Rng + SeedableRng + Send + 'a,
+    F: FnMut(Feature) -> Result<()>,
+>(
+    input: I,
+    zones: Z,
+    rng: &mut R,
     options: Options,
     mut output: F,
-) -> Result<()> {
-    let csv_path = csv_path.as_ref();
+) -> Result<Summary> {
+    let mut trips = disaggregate_trips(input, zones, rng, options)?;
+    for trip in &mut trips {
+        output(trip?.into())?;
+    }
+    trips.finish()
+}
 
-    let points_per_origin_zone: Option<BTreeMap<String, Vec<WeightedPoint>>> =
-        if let Subsample::WeightedPoints(points) = options.subsample_origin {
-            Some(points_per_polygon(points, zones))
-        } else {
-            None
+/// Like `disaggregate`, but returns the output as an iterator of `JitteredTrip`s, instead of
+/// passing features to a callback. Unless `Integerisation::TruncateReplicateSample` is used, input
+/// rows are only read as the iterator is consumed.
+pub fn disaggregate_trips<
+    'a,
+    I: Into<OdInput>,
+    Z: Into<Zones<'a>>,
+    R: Rng + SeedableRng + Send + 'a,
+>(
+    input: I,
+    zones: Z,
+    rng: &mut R,
+    options: Options,
+) -> Result<JitteredTrips<'a>> {
+    let input = input.into();
+    let zones = zones.into();
+    let input_name = input.name().to_string();
+    let keys = Keys::new(&options);
+
+    let constraints = PairConstraints::new(&options);
+    let metric = options.crs.metric();
+    let points_per_origin_zone =
+        SubpointsPerZone::new(options.subsample_origin, zones.origins, metric)?;
+    let points_per_destination_zone =
+        SubpointsPerZone::new(options.subsample_destination, zones.destinations, metric)?;
+    let reproject = options.reproject_to_wgs84.then(|| options.crs.clone());
+    let base_seed: u64 = rng.gen();
+
+    let mut checked_upfront = false;
+    if constraints.needs_feasibility_check()
+        || (constraints.retry_fallback == RetryFallback::Error
+            && !options.max_distance_meters_per_mode.is_empty())
+    {
+        if let Some(records) = input.reread_records(&keys)? {
+            check_feasibility(
+                records.map(|record| {
+                    let record = record?;
+                    // Only check modes with some trips
+                    let per_mode = record
+                        .columns
+                        .iter()
+                        .filter(|(_, value)| matches!(value, OdValue::Count(x) if *x > 0.0))
+                        .map(|(mode, _)| {
+                            constraints.for_mode(&options.max_distance_meters_per_mode, mode)
+                        })
+                        .collect();
+                    Ok((record.origin, record.destination, per_mode))
+                }),
+                zones,
+                &points_per_origin_zone,
+                &points_per_destination_zone,
+            )?;
+            checked_upfront = true;
+        }
+    }
+
+    let keep_rows = matches!(options.unknown_zones, UnknownZones::Reject(_));
+    let mut records = input.into_records(keys.clone(), keep_rows)?;
+    // For truncate-replicate-sample, decide upfront which counts to round up
+    let mut round_up: HashSet<(usize, String)> = HashSet::new();
+    if options.integerisation == Integerisation::TruncateReplicateSample {
+        let all_records = records.collect::<Result<Vec<InputRecord>>>()?;
+        round_up = truncate_replicate_sample(&all_records, rng);
+        records = Box::new(all_records.into_iter().map(Ok));
+    }
+    let summary = SummaryBuilder::new(&options.unknown_zones, &keys)?;
+
+    eprintln!("Disaggregating OD data");
+    let handle_batch = move |batch: Vec<(usize, InputRecord)>,
+                             summary: &mut SummaryBuilder,
+                             trips: &mut VecDeque<JitteredTrip>|
+          -> Result<()> {
+        let process = |row_idx, input: InputRecord| {
+            let InputRecord { record, row } = input;
+            let missing = unknown_zones(
+                zones,
+                &record.origin,
+                &record.destination,
+                &options.unknown_zones,
+            )?;
+            if !missing.is_empty() {
+                let trips = record
+                    .columns
+                    .iter()
+                    .filter_map(|(_, value)| match value {
+                        OdValue::Count(count) => Some(count.max(0.0)),
+                        OdValue::Attribute(_) => None,
+                    })
+                    .sum();
+                return Ok(RowOutcome::Skipped(SkippedRow {
+                    record,
+                    row,
+                    missing,
+                    trips,
+                }));
+            }
+            let target_distance_meters =
+                target_distance(&record, &input_name, &options.distance_key)?;
+            let (origin_id, destination_id) = (record.origin, record.destination);
+
+            let (origin_sampler, destination_sampler) = samplers_for_row(
+                zones,
+                &points_per_origin_zone,
+                &points_per_destination_zone,
+                &origin_id,
+                &destination_id,
+            )?;
+
+            let mut rng: R = rng_for_row(base_seed, row_idx);
+            let mut seen_pairs: HashSet<ODPair> = HashSet::new();
+            let mut row_trips = Vec::new();
+            let mut stats = RowStats::default();
+
+            // Every count is a mode. Handle modes in the order of the columns, so the output is
+            // deterministic.
+            for (mode, value) in record.columns {
+                if let OdValue::Count(count) = value {
+                    let count = count.max(0.0);
+                    let count = match options.integerisation {
+                        Integerisation::Truncate => count as usize,
+                        Integerisation::Round => count.round() as usize,
+                        Integerisation::Stochastic => {
+                            let fraction = count.fract();
+                            if fraction > 0.0 && rng.gen_bool(fraction) {
+                                count as usize + 1
+                            } else {
+                                count as usize
+                            }
+                        }
+                        Integerisation::TruncateReplicateSample => {
+                            if round_up.contains(&(row_idx, mode.clone())) {
+                                count as usize + 1
+                            } else {
+                                count as usize
+                            }
+                        }
+                    };
+
+                    let constraints = constraints
+                        .for_mode(&options.max_distance_meters_per_mode, &mode)
+                        .with_target(target_distance_meters);
+                    if count > 0 && !checked_upfront {
+                        constraints.check_feasible(
+                            &origin_sampler,
+                            &destination_sampler,
+                            &origin_id,
+                            &destination_id,
+                        )?;
+                    }
+                    if constraints.deduplicate_pairs {
+                        if let (Some(num_origin), Some(num_destination)) = (
+                            origin_sampler.num_points(),
+                            destination_sampler.num_points(),
+                        ) {
+                            if count > num_origin * num_destination {
+                                bail!("{count} unique pairs requested for {mode} from {origin_id} ({num_origin} subpoints) to {destination_id} ({num_destination} subpoints), but this is impossible");
+                            }
+                        }
+                    }
+
+                    for _ in 0..count {
+                        let (o, d) = if let Some(pair) = sample_pair(
+                            &origin_sampler,
+                            &destination_sampler,
+                            &constraints,
+                            &mut seen_pairs,
+                            &mut rng,
+                        ) {
+                            pair
+                        } else {
+                            stats.no_pair_found(&constraints, &origin_id, &destination_id)?;
+                            continue;
+                        };
+                        stats.record(&constraints, o, d);
+                        let mut json_map: Map<String, Value> = Map::new();
+                        json_map.insert("mode".to_string(), Value::String(mode.clone()));
+                        row_trips.push(JitteredTrip {
+                            origin: o,
+                            destination: d,
+                            origin_zone: origin_id.clone(),
+                            destination_zone: destination_id.clone(),
+                            properties: json_map,
+                        });
+                    }
+                }
+            }
+            Ok(RowOutcome::Jittered((row_trips, stats)))
         };
-    let points_per_destination_zone: Option<BTreeMap<String, Vec<WeightedPoint>>> =
-        if let Subsample::WeightedPoints(points) = options.subsample_destination {
-            Some(points_per_polygon(points, zones))
+
+        for outcome in process_batch(batch, process) {
+            let (row_trips, stats) = match outcome? {
+                RowOutcome::Jittered(result) => result,
+                RowOutcome::Skipped(skipped) => {
+                    summary.skip_row(skipped)?;
+                    continue;
+                }
+            };
+            summary.add_stats(&stats);
+            trips.extend(row_trips);
+        }
+        Ok(())
+    };
+    Ok(JitteredTrips::new(
+        records,
+        handle_batch,
+        summary,
+        reproject,
+    ))
+}
+
+/// For every numeric mode column, decides which rows should round their fractional count up, so
+/// that the total after rounding matches the original.
+fn truncate_replicate_sample<R: Rng>(
+    records: &[InputRecord],
+    rng: &mut R,
+) -> HashSet<(usize, String)> {
+    // Per column, the fractional parts of every row with one
+    let mut fractions_per_column: BTreeMap<&str, Vec<(usize, f64)>> = BTreeMap::new();
+    for (row_idx, InputRecord { record, .. }) in records.iter().enumerate() {
+        for (key, value) in &record.columns {
+            if let OdValue::Count(count) = value {
+                let fraction = count.max(0.0).fract();
+                if fraction > 0.0 {
+                    fractions_per_column
+                        .entry(key)
+                        .or_default()
+                        .push((row_idx, fraction));
+                }
+            }
+        }
+    }
+
+    let mut round_up = HashSet::new();
+    for (key, fractions) in fractions_per_column {
+        let deficit = fractions.iter().map(|(_, x)| x).sum::<f64>().round() as usize;
+        // Each fraction is below 1, so there are always at least this many candidates
+        let deficit = deficit.min(fractions.len());
+        let picked = rand::seq::index::sample_weighted(
+            rng,
+            fractions.len(),
+            |idx| fractions[idx].1,
+            deficit,
+        )
+        // The weights are all positive
+        .unwrap();
+        for idx in picked {
+            round_up.insert((fractions[idx].0, key.to_string()));
+        }
+    }
+    round_up
+}
+
+/// The result of jittering one row, before checking for duplicate pairs across all rows.
+struct JitteredRow<R> {
+    origin_id: String,
+    destination_id: String,
+    properties: Map<String, Value>,
+    pairs: Vec<(Point<f64>, Point<f64>)>,
+    /// The constraints for this row, including its target distance
+    constraints: PairConstraints,
+    /// Pairs already used by this row
+    row_pairs: HashSet<ODPair>,
+    /// The row's RNG, used to resample duplicate pairs
+    rng: R,
+    stats: RowStats,
+}
+
+/// The result of processing one row.
+enum RowOutcome<T> {
+    Jittered(T),
+    Skipped(SkippedRow),
+}
+
+/// An input row referencing unknown zones.
+struct SkippedRow {
+    record: OdRecord,
+    /// The original row, if it came from a table and rejected rows are written
+    row: Option<Row>,
+    missing: Vec<String>,
+    trips: f64,
+}
+
+/// Builds up a `Summary` as rows are handled, optionally writing skipped rows to a CSV file.
+struct SummaryBuilder {
+    rejects: Option<(csv::Writer<File>, Keys)>,
+    summary: Summary,
+    stats: RowStats,
+}
+
+impl SummaryBuilder {
+    fn new(policy: &UnknownZones, keys: &Keys) -> Result<SummaryBuilder> {
+        let rejects = if let UnknownZones::Reject(path) = policy {
+            Some((csv::Writer::from_writer(File::create(path)?), keys.clone()))
         } else {
             None
         };
+        Ok(SummaryBuilder {
+            rejects,
+            summary: Summary::default(),
+            stats: RowStats::default(),
+        })
+    }
 
-    println!("Disaggregating OD data");
-    for rec in csv::Reader::from_reader(File::open(csv_path)?).deserialize() {
-        // It's tempting to deserialize directly into a serde_json::Map<String, Value> and
-        // auto-detect strings and numbers. But sadly, some input data has zone names that look
-        // numeric, and even contain leading zeros, which'll be lost. So first just grab raw
-        // strings
-        let mut string_map: HashMap<String, String> = rec?;
+    fn skip_row(&mut self, skipped: SkippedRow) -> Result<()> {
+        for zone in skipped.missing {
+            if !self.summary.unknown_zones.contains_key(&zone) {
+                eprintln!("Warning: skipping rows referencing unknown zone {zone}");
+            }
+            *self.summary.unknown_zones.entry(zone).or_default() += skipped.trips;
+        }
+        if let Some((ref mut writer, ref keys)) = self.rejects {
+            let (header, fields): (Vec<String>, Vec<String>) = match skipped.row {
+                Some(row) => row.into_iter().unzip(),
+                None => keys.to_csv(&skipped.record),
+            };
+            if self.summary.rows_skipped == 0 {
+                writer.write_record(header)?;
+            }
+            writer.write_record(fields)?;
+        }
+        self.summary.rows_skipped += 1;
+        self.summary.trips_skipped += skipped.trips;
+        Ok(())
+    }
+
+    fn add_stats(&mut self, stats: &RowStats) {
+        self.stats.dropped += stats.dropped;
+        self.stats.violating += stats.violating;
+        self.stats.distance_trips += stats.distance_trips;
+        self.stats.target_meters += stats.target_meters;
+        self.stats.achieved_meters += stats.achieved_meters;
+        self.stats.absolute_error_meters += stats.absolute_error_meters;
+    }
 
-        let origin_id = if let Some(id) = string_map.remove(&options.origin_key) {
-            id
+    fn finish(mut self) -> Result<Summary> {
+        if let Some((mut writer, _)) = self.rejects {
+            writer.flush()?;
+        }
+        let stats = self.stats;
+        self.summary.trips_dropped = stats.dropped;
+        self.summary.trips_violating_constraints = stats.violating;
+        if stats.distance_trips > 0 {
+            let n = stats.distance_trips as f64;
+            self.summary.distances = Some(DistanceSummary {
+                trips: stats.distance_trips,
+                mean_target_meters: stats.target_meters / n,
+                mean_achieved_meters: stats.achieved_meters / n,
+                mean_absolute_error_meters: stats.absolute_error_meters / n,
+            });
+        }
+        Ok(self.summary)
+    }
+}
+
+/// How many rows to read before processing them. With the `parallel` feature, rows in a batch are
+/// handled on different threads.
+const BATCH_SIZE: usize = 10_000;
+
+/// Transforms each row in a batch with `process`, keeping the results in the original row order.
+/// With the `parallel` feature, `process` runs on many threads. Each row gets its own RNG (see
+/// `rng_for_row`), so the output doesn't depend on the number of threads.
+fn process_batch<Row, T, P>(batch: Vec<(usize, Row)>, process: P) -> Vec<Result<T>>
+where
+    Row: Send,
+    T: Send,
+    P: Fn(usize, Row) -> Result<T> + Sync,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        batch
+            .into_par_iter()
+            .map(|(row_idx, row)| process(row_idx, row))
+            .collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        batch
+            .into_iter()
+            .map(|(row_idx, row)| process(row_idx, row))
+            .collect()
+    }
+}
+
+/// One trip produced by `jitter_trips` or `disaggregate_trips`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JitteredTrip {
+    /// The point sampled from the origin zone
+    pub origin: Point<f64>,
+    /// The point sampled from the destination zone
+    pub destination: Point<f64>,
+    /// The name of the origin zone
+    pub origin_zone: String,
+    /// The name of the destination zone
+    pub destination_zone: String,
+    /// The same properties that `jitter` or `disaggregate` would output for this trip
+    pub properties: Map<String, Value>,
+}
+
+impl From<JitteredTrip> for Feature {
+    /// Makes a LineString from the origin to the destination.
+    fn from(trip: JitteredTrip) -> Self {
+        to_geojson(trip.origin, trip.destination, trip.properties)
+    }
+}
+
+type HandleBatch<'a> = Box<
+    dyn FnMut(
+            Vec<(usize, InputRecord)>,
+            &mut SummaryBuilder,
+            &mut VecDeque<JitteredTrip>,
+        ) -> Result<()>
+        + 'a,
+>;
+
+/// An iterator over the output of `jitter_trips` or `disaggregate_trips`. Input rows are read and
+/// transformed in batches as the iterator is consumed. After the first error, the iterator ends.
+pub struct JitteredTrips<'a> {
+    records: std::iter::Enumerate<Box<dyn Iterator<Item = Result<InputRecord>>>>,
+    handle_batch: HandleBatch<'a>,
+    summary: SummaryBuilder,
+    buffer: VecDeque<JitteredTrip>,
+    /// Transform output from this CRS to WGS84
+    reproject: Option<Crs>,
+    error: Option<anyhow::Error>,
+    done: bool,
+}
+
+impl<'a> JitteredTrips<'a> {
+    fn new<H>(
+        records: Box<dyn Iterator<Item = Result<InputRecord>>>,
+        handle_batch: H,
+        summary: SummaryBuilder,
+        reproject: Option<Crs>,
+    ) -> Self
+    where
+        H: FnMut(
+                Vec<(usize, InputRecord)>,
+                &mut SummaryBuilder,
+                &mut VecDeque<JitteredTrip>,
+            ) -> Result<()>
+            + 'a,
+    {
+        Self {
+            records: records.enumerate(),
+            handle_batch: Box::new(handle_batch),
+            summary,
+            buffer: VecDeque::new(),
+            reproject,
+            error: None,
+            done: false,
+        }
+    }
+
+    /// Finishes writing any rejected rows and summarizes what happened. If the iterator wasn't
+    /// fully consumed, this only describes the input rows read so far.
+    pub fn finish(self) -> Result<Summary> {
+        self.summary.finish()
+    }
+
+    /// Reads and transforms the next batch of input rows.
+    fn next_batch(&mut self) -> Result<()> {
+        let mut batch = Vec::new();
+        for (row_idx, record) in self.records.by_ref().take(BATCH_SIZE) {
+            batch.push((row_idx, record?));
+        }
+        if batch.is_empty() {
+            self.done = true;
+            return Ok(());
+        }
+        (self.handle_batch)(batch, &mut self.summary, &mut self.buffer)
+    }
+}
+
+impl<'a> Iterator for JitteredTrips<'a> {
+    type Item = Result<JitteredTrip>;
+
+    fn next(&mut self) -> Option<Result<JitteredTrip>> {
+        loop {
+            // Trips from rows before an error are still returned first
+            if let Some(mut trip) = self.buffer.pop_front() {
+                if let Some(ref crs) = self.reproject {
+                    match (crs.to_wgs84(trip.origin), crs.to_wgs84(trip.destination)) {
+                        (Ok(origin), Ok(destination)) => {
+                            trip.origin = origin;
+                            trip.destination = destination;
+                        }
+                        (Err(err), _) | (_, Err(err)) => {
+                            self.buffer.clear();
+                            self.done = true;
+                            return Some(Err(err));
+                        }
+                    }
+                }
+                return Some(Ok(trip));
+            }
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.next_batch() {
+                self.error = Some(err);
+                self.done = true;
+            }
+        }
+    }
+}
+
+/// Creates an independent RNG for one input row, derived from a base seed and the row's index.
+/// This works for any RNG, no matter the size of its seed.
+fn rng_for_row<R: SeedableRng>(base_seed: u64, row_idx: usize) -> R {
+    R::seed_from_u64(splitmix64(base_seed ^ splitmix64(row_idx as u64)))
+}
+
+/// Scrambles bits, so nearby inputs like consecutive row indices give unrelated outputs.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Finds the target distance of a record, if there is one. An empty value means there isn't.
+fn target_distance(
+    record: &OdRecord,
+    input_name: &str,
+    distance_key: &Option<String>,
+) -> Result<Option<f64>> {
+    let key = if let Some(key) = distance_key {
+        key
+    } else {
+        return Ok(None);
+    };
+    let value = record
+        .columns
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value);
+    match value {
+        Some(OdValue::Attribute(Value::Null)) => Ok(None),
+        Some(OdValue::Attribute(Value::Number(x))) => match x.as_f64() {
+            Some(x) if x < 0.0 => bail!(
+                "{} has a negative {} value {}; set distance_key properly",
+                input_name,
+                key,
+                x
+            ),
+            x => Ok(x),
+        },
+        _ => bail!(
+            "{} doesn't have a {} column or the value isn't numeric; set distance_key properly",
+            input_name,
+            key
+        ),
+    }
+}
+
+/// Returns the origin and destination zones of a row that aren't in `zones`, or fails if `policy`
+/// says to.
+fn unknown_zones(
+    zones: Zones,
+    origin_id: &str,
+    destination_id: &str,
+    policy: &UnknownZones,
+) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    if !zones.has_origin(origin_id) {
+        if *policy == UnknownZones::Fail {
+            bail!("Unknown origin zone {origin_id}");
+        }
+        missing.push(origin_id.to_string());
+    }
+    if !zones.has_destination(destination_id) && !missing.iter().any(|zone| zone == destination_id)
+    {
+        if *policy == UnknownZones::Fail {
+            bail!("Unknown destination zone {destination_id}");
+        }
+        missing.push(destination_id.to_string());
+    }
+    Ok(missing)
+}
+
+fn samplers_for_row<'a>(
+    zones: Zones<'a>,
+    points_per_origin_zone: &'a SubpointsPerZone,
+    points_per_destination_zone: &'a SubpointsPerZone,
+    origin_id: &str,
+    destination_id: &str,
+) -> Result<(Subsampler<'a>, Subsampler<'a>)> {
+    let origin_sampler = if let Some(point) = zones.origin_point(origin_id) {
+        Subsampler::Fixed(point)
+    } else if let Some(zone) = zones.origins.get(origin_id) {
+        Subsampler::new(points_per_origin_zone, zone, origin_id)?
+    } else {
+        bail!("Unknown origin zone {origin_id}");
+    };
+    let destination_sampler = if let Some(point) = zones.destination_point(destination_id) {
+        Subsampler::Fixed(point)
+    } else if let Some(zone) = zones.destinations.get(destination_id) {
+        Subsampler::new(points_per_destination_zone, zone, destination_id)?
+    } else {
+        bail!("Unknown destination zone {destination_id}");
+    };
+    Ok((origin_sampler, destination_sampler))
+}
+
+/// The constraints on every sampled pair of origin and destination points.
+#[derive(Clone, Copy)]
+struct PairConstraints {
+    min_distance_meters: f64,
+    /// Infinity if there's no maximum
+    max_distance_meters: f64,
+    deduplicate_pairs: bool,
+    max_attempts: usize,
+    retry_fallback: RetryFallback,
+    /// Pick the pair closest to this distance
+    target_distance_meters: Option<f64>,
+    distance_candidates: usize,
+    metric: Metric,
+}
+
+impl PairConstraints {
+    fn new(options: &Options) -> PairConstraints {
+        PairConstraints {
+            min_distance_meters: options.min_distance_meters,
+            max_distance_meters: options.max_distance_meters.unwrap_or(f64::INFINITY),
+            deduplicate_pairs: options.deduplicate_pairs,
+            max_attempts: options.max_attempts,
+            retry_fallback: options.retry_fallback,
+            target_distance_meters: None,
+            distance_candidates: options.distance_candidates,
+            metric: options.crs.metric(),
+        }
+    }
+
+    fn with_target(&self, target_distance_meters: Option<f64>) -> PairConstraints {
+        let mut constraints = *self;
+        constraints.target_distance_meters = target_distance_meters;
+        constraints
+    }
+
+    /// Applies the maximum distance for one mode, if there is one.
+    fn for_mode(
+        &self,
+        max_distance_per_mode: &HashMap<String, f64>,
+        mode: &str,
+    ) -> PairConstraints {
+        let mut constraints = *self;
+        if let Some(max) = max_distance_per_mode.get(mode) {
+            constraints.max_distance_meters = *max;
+        }
+        constraints
+    }
+
+    /// How many meters the pair is from satisfying the distance constraints, or 0 if it does.
+    fn violation(&self, o: Point<f64>, d: Point<f64>) -> f64 {
+        let distance = self.metric.distance(o, d);
+        (self.min_distance_meters - distance).max(0.0)
+            + (distance - self.max_distance_meters).max(0.0)
+    }
+
+    fn needs_feasibility_check(&self) -> bool {
+        self.retry_fallback == RetryFallback::Error
+            && (self.min_distance_meters > 0.0 || self.max_distance_meters.is_finite())
+    }
+
+    fn describe(&self) -> String {
+        if self.max_distance_meters.is_finite() {
+            format!(
+                "between {} and {} meters apart",
+                self.min_distance_meters, self.max_distance_meters
+            )
         } else {
+            format!("at least {} meters apart", self.min_distance_meters)
+        }
+    }
+
+    /// With `RetryFallback::Error`, fails quickly if no points sampled from these zones could
+    /// satisfy the distance constraints, instead of trying `max_attempts` times for every trip.
+    fn check_feasible(
+        &self,
+        origin_sampler: &Subsampler,
+        destination_sampler: &Subsampler,
+        origin_id: &str,
+        destination_id: &str,
+    ) -> Result<()> {
+        if self.needs_feasibility_check() && !self.feasible(origin_sampler, destination_sampler) {
             bail!(
-                "{} doesn't have a {} column; set origin_key properly",
-                csv_path.display(),
-                options.origin_key
+                "No points from {origin_id} to {destination_id} can be {}",
+                self.describe()
             );
+        }
+        Ok(())
+    }
+
+    /// Could any points sampled from these zones satisfy the distance constraints? This only
+    /// compares bounding boxes, so it may say yes when the answer is no.
+    fn feasible(&self, origin_sampler: &Subsampler, destination_sampler: &Subsampler) -> bool {
+        let (o_bounds, d_bounds) = (origin_sampler.bounds(), destination_sampler.bounds());
+
+        let corners = |bounds: Rect<f64>| {
+            let (min, max) = (bounds.min(), bounds.max());
+            [
+                Point::new(min.x, min.y),
+                Point::new(min.x, max.y),
+                Point::new(max.x, min.y),
+                Point::new(max.x, max.y),
+            ]
         };
-        let destination_id = if let Some(id) = string_map.remove(&options.destination_key) {
-            id
-        } else {
+        // The furthest two points in the boxes are corners
+        let mut max_distance: f64 = 0.0;
+        for o in corners(o_bounds) {
+            for d in corners(d_bounds) {
+                max_distance = max_distance.max(self.metric.distance(o, d));
+            }
+        }
+
+        // Along each axis, the closest two points are on facing edges, or the same if the boxes
+        // overlap
+        let closest = |o_min: f64, o_max: f64, d_min: f64, d_max: f64| {
+            if o_max < d_min {
+                (o_max, d_min)
+            } else if d_max < o_min {
+                (o_min, d_max)
+            } else {
+                (o_min.max(d_min), o_min.max(d_min))
+            }
+        };
+        let (o_x, d_x) = closest(
+            o_bounds.min().x,
+            o_bounds.max().x,
+            d_bounds.min().x,
+            d_bounds.max().x,
+        );
+        let (o_y, d_y) = closest(
+            o_bounds.min().y,
+            o_bounds.max().y,
+            d_bounds.min().y,
+            d_bounds.max().y,
+        );
+        let min_distance = self
+            .metric
+            .distance(Point::new(o_x, o_y), Point::new(d_x, d_y));
+
+        max_distance >= self.min_distance_meters && min_distance <= self.max_distance_meters
+    }
+}
+
+/// Counts trips that didn't satisfy the constraints in `PairConstraints`, and how close trips are
+/// to their target distance.
+#[derive(Default)]
+struct RowStats {
+    dropped: usize,
+    violating: usize,
+    /// The rest are totals over trips with a target distance
+    distance_trips: usize,
+    target_meters: f64,
+    achieved_meters: f64,
+    absolute_error_meters: f64,
+}
+
+impl RowStats {
+    fn record(&mut self, constraints: &PairConstraints, o: Point<f64>, d: Point<f64>) {
+        if constraints.violation(o, d) > 0.0 {
+            self.violating += 1;
+        }
+        if let Some(target) = constraints.target_distance_meters {
+            let distance = constraints.metric.distance(o, d);
+            self.distance_trips += 1;
+            self.target_meters += target;
+            self.achieved_meters += distance;
+            self.absolute_error_meters += (distance - target).abs();
+        }
+    }
+
+    fn no_pair_found(
+        &mut self,
+        constraints: &PairConstraints,
+        origin_id: &str,
+        destination_id: &str,
+    ) -> Result<()> {
+        if constraints.retry_fallback == RetryFallback::Error {
             bail!(
-                "{} doesn't have a {} column; set destination_key properly",
-                csv_path.display(),
-                options.destination_key
+                "Couldn't find a pair of points from {origin_id} to {destination_id} {} after {} attempts",
+                constraints.describe(),
+                constraints.max_attempts
             );
-        };
-        let origin_zone = if let Some(zone) = zones.get(&origin_id) {
-            zone
-        } else {
-            bail!("Unknown origin zone {origin_id}");
-        };
-        let destination_zone = if let Some(zone) = zones.get(&destination_id) {
-            zone
-        } else {
-            bail!("Unknown destination zone {destination_id}");
-        };
-        let origin_sampler = Subsampler::new(&points_per_origin_zone, origin_zone, &origin_id)?;
-        let destination_sampler = Subsampler::new(
-            &points_per_destination_zone,
-            destination_zone,
+        }
+        self.dropped += 1;
+        Ok(())
+    }
+}
+
+/// Before producing any output, makes sure it's possible to satisfy the distance constraints for
+/// every row. Each requirement is an origin zone, a destination zone, and the constraints that
+/// trips between them need to satisfy. Every distinct requirement is only checked once, and
+/// requirements with unknown zones are handled later.
+fn check_feasibility(
+    requirements: impl Iterator<Item = Result<(String, String, Vec<PairConstraints>)>>,
+    zones: Zones,
+    points_per_origin_zone: &SubpointsPerZone,
+    points_per_destination_zone: &SubpointsPerZone,
+) -> Result<()> {
+    // Only the maximum distance varies between requirements for the same zones
+    let mut checked: HashSet<(String, String, u64)> = HashSet::new();
+    for requirement in requirements {
+        let (origin_id, destination_id, all_constraints) = requirement?;
+        if !zones.has_origin(&origin_id) || !zones.has_destination(&destination_id) {
+            continue;
+        }
+        let unchecked: Vec<PairConstraints> = all_constraints
+            .into_iter()
+            .filter(|constraints| {
+                checked.insert((
+                    origin_id.clone(),
+                    destination_id.clone(),
+                    constraints.max_distance_meters.to_bits(),
+                ))
+            })
+            .collect();
+        if unchecked.is_empty() {
+            continue;
+        }
+        let (origin_sampler, destination_sampler) = samplers_for_row(
+            zones,
+            points_per_origin_zone,
+            points_per_destination_zone,
+            &origin_id,
             &destination_id,
         )?;
+        for constraints in unchecked {
+            constraints.check_feasible(
+                &origin_sampler,
+                &destination_sampler,
+                &origin_id,
+                &destination_id,
+            )?;
+        }
+    }
+    Ok(())
+}
 
-        let mut seen_pairs: HashSet<ODPair> = HashSet::new();
-
-        // Interpret all columns except origin_key and destination_key as numeric, split by mode
-        for (mode, value) in string_map {
-            if let Ok(count) = value.parse::<f64>() {
-                // TODO How should we treat fractional input?
-                let count = count as usize;
-
-                if options.deduplicate_pairs {
-                    if let (Some(num_origin), Some(num_destination)) = (
-                        origin_sampler.num_points(),
-                        destination_sampler.num_points(),
-                    ) {
-                        if count > num_origin * num_destination {
-                            bail!("{count} unique pairs requested for {mode} from {origin_id} ({num_origin} subpoints) to {destination_id} ({num_destination} subpoints), but this is impossible");
-                        }
-                    }
-                }
-
-                for _ in 0..count {
-                    loop {
-                        let o = origin_sampler.sample(rng);
-                        let d = destination_sampler.sample(rng);
-                        if o.haversine_distance(&d) >= options.min_distance_meters {
-                            if options.deduplicate_pairs {
-                                let pair = hashify(o, d);
-                                if seen_pairs.contains(&pair) {
-                                    continue;
-                                } else {
-                                    seen_pairs.insert(pair);
-                                }
-                            }
-
-                            let mut json_map: Map<String, Value> = Map::new();
-                            json_map.insert("mode".to_string(), Value::String(mode.clone()));
-                            output(to_geojson(o, d, json_map))?;
-                            break;
-                        }
-                    }
-                }
+/// Samples an origin and destination point until they satisfy the distance constraints. If
+/// `deduplicate_pairs` is true, also keeps going until the pair isn't in `seen_pairs`, then
+/// remembers it. With a target distance, keeps going until `distance_candidates` valid pairs are
+/// found, and picks the one closest to the target. Gives up after `max_attempts`, returning
+/// nothing, or the pair closest to satisfying the constraints with
+/// `RetryFallback::AcceptClosest`.
+fn sample_pair<R: Rng>(
+    origin_sampler: &Subsampler,
+    destination_sampler: &Subsampler,
+    constraints: &PairConstraints,
+    seen_pairs: &mut HashSet<ODPair>,
+    rng: &mut R,
+) -> Option<(Point<f64>, Point<f64>)> {
+    // Valid pairs, along with how far they are from the target distance
+    let mut best_valid: Option<(f64, Point<f64>, Point<f64>)> = None;
+    let mut num_valid = 0;
+    // Invalid pairs, along with how badly they violate the distance constraints
+    let mut closest: Option<(f64, Point<f64>, Point<f64>)> = None;
+    for _ in 0..constraints.max_attempts {
+        let o = origin_sampler.sample(rng);
+        let d = destination_sampler.sample(rng);
+        if constraints.deduplicate_pairs && seen_pairs.contains(&hashify(o, d)) {
+            continue;
+        }
+        let violation = constraints.violation(o, d);
+        if violation == 0.0 {
+            let target = if let Some(target) = constraints.target_distance_meters {
+                target
+            } else {
+                best_valid = Some((0.0, o, d));
+                break;
+            };
+            let error = (constraints.metric.distance(o, d) - target).abs();
+            if best_valid.map(|(best, _, _)| error < best).unwrap_or(true) {
+                best_valid = Some((error, o, d));
             }
+            num_valid += 1;
+            if num_valid >= constraints.distance_candidates {
+                break;
+            }
+        } else if closest.map(|(best, _, _)| violation < best).unwrap_or(true) {
+            closest = Some((violation, o, d));
         }
     }
-    Ok(())
+
+    let (_, o, d) = match (best_valid, constraints.retry_fallback) {
+        (Some(best), _) => best,
+        (None, RetryFallback::AcceptClosest) => closest?,
+        (None, _) => return None,
+    };
+    if constraints.deduplicate_pairs {
+        seen_pairs.insert(hashify(o, d));
+    }
+    Some((o, d))
 }
 
-/// Extract multipolygon zones from a GeoJSON file, using the provided `name_key` as the key in the
-/// resulting map.
-pub fn load_zones(
-    geojson_path: &str,
-    name_key: &str,
-) -> Result<HashMap<String, MultiPolygon<f64>>> {
-    let reader = FeatureReader::from_reader(BufReader::new(File::open(geojson_path)?));
+/// Extract multipolygon zones from a file in any format `read_features` supports, using the
+/// provided `name_key` as the key in the resulting map.
+pub fn load_zones(path: &str, name_key: &str) -> Result<HashMap<String, MultiPolygon<f64>>> {
     let mut zones: HashMap<String, MultiPolygon<f64>> = HashMap::new();
-    for feature in reader.features() {
+    for feature in read_features(path)? {
         let feature = feature?;
         if let Some(zone_name) = feature
             .property(name_key)
             .and_then(|x| x.as_str())
             .map(|x| x.to_string())
         {
-            let gj_geom: geojson::Geometry = feature.geometry.unwrap();
-            let geo_geometry: geo_types::Geometry<f64> = gj_geom.try_into().unwrap();
+            let geo_geometry: geo_types::Geometry<f64> = match feature.geometry {
+                Some(geometry) => geometry.try_into()?,
+                None => bail!("Zone {zone_name} in {path} doesn't have a geometry"),
+            };
             if let geo_types::Geometry::MultiPolygon(mp) = geo_geometry {
                 zones.insert(zone_name, mp);
             } else if let geo_types::Geometry::Polygon(p) = geo_geometry {
@@ -399,6 +1653,35 @@ pub fn load_zones(
     Ok(zones)
 }
 
+/// Extract points, like schools or hospitals, from a file in any format `read_features` supports,
+/// using the provided `id_key` as the key in the resulting map. IDs can be strings or integers.
+pub fn load_points(path: &str, id_key: &str) -> Result<HashMap<String, Point<f64>>> {
+    let mut points = HashMap::new();
+    for feature in read_features(path)? {
+        let feature = feature?;
+        let id = match feature.property(id_key) {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) if id.is_i64() || id.is_u64() => id.to_string(),
+            _ => bail!("Feature doesn't have a string or integer ID {id_key}: {feature:?}"),
+        };
+        let geometry: Option<geo_types::Geometry<f64>> = match feature.geometry {
+            Some(geometry) => Some(geometry.try_into()?),
+            None => None,
+        };
+        let point = match geometry {
+            Some(geo_types::Geometry::Point(point)) => point,
+            Some(geo_types::Geometry::MultiPoint(multi_point)) if multi_point.0.len() == 1 => {
+                multi_point.0[0]
+            }
+            _ => bail!("{id} in {path} isn't a point"),
+        };
+        if points.insert(id.clone(), point).is_some() {
+            bail!("{path} has more than one point with {id_key} = {id}");
+        }
+    }
+    Ok(points)
+}
+
 // TODO Share with rampfs
 fn points_per_polygon(
     points: Vec<WeightedPoint>,
@@ -423,6 +1706,280 @@ fn points_per_polygon(
     output
 }
 
+fn lines_per_polygon(
+    lines: Vec<WeightedLineString>,
+    polygons: &HashMap<String, MultiPolygon<f64>>,
+    metric: Metric,
+) -> BTreeMap<String, Vec<WeightedLine>> {
+    // Empty LineStrings have nothing to sample, and no envelope to index
+    let tree = RTree::bulk_load(
+        lines
+            .into_iter()
+            .filter(|line| line.line_string.bounding_rect().is_some())
+            .collect(),
+    );
+
+    let mut output = BTreeMap::new();
+    for (key, polygon) in polygons {
+        let mut lines_inside = Vec::new();
+        let bounds = polygon.bounding_rect().unwrap();
+        let min = bounds.min();
+        let max = bounds.max();
+        let envelope: AABB<[f64; 2]> = AABB::from_corners([min.x, min.y], [max.x, max.y]);
+        for candidate in tree.locate_in_envelope_intersecting(&envelope) {
+            // Only keep the parts of the LineString inside the zone. Most lines are entirely
+            // inside, so only clip the ones crossing the boundary.
+            let lines: Vec<Line<f64>> = if polygon.contains(&candidate.line_string) {
+                candidate.line_string.lines().collect()
+            } else {
+                candidate
+                    .line_string
+                    .lines()
+                    .flat_map(|line| clip_line(polygon, line))
+                    .collect()
+            };
+            for line in lines {
+                let weight = candidate.weight * metric.length(line);
+                if weight > 0.0 {
+                    lines_inside.push(WeightedLine { line, weight });
+                }
+            }
+        }
+        output.insert(key.clone(), lines_inside);
+    }
+    output
+}
+
+/// Splits a line where it crosses the boundary of a polygon, keeping the pieces inside. Unlike
+/// geo's boolean operations, this can't panic.
+fn clip_line(polygon: &MultiPolygon<f64>, line: Line<f64>) -> Vec<Line<f64>> {
+    let delta = line.delta();
+    let length_squared = delta.x * delta.x + delta.y * delta.y;
+    if length_squared == 0.0 {
+        return Vec::new();
+    }
+    // How far along the line each crossing is, from 0 to 1
+    let fraction = |pt: Coord<f64>| {
+        ((pt.x - line.start.x) * delta.x + (pt.y - line.start.y) * delta.y) / length_squared
+    };
+    let mut fractions = vec![0.0, 1.0];
+    for boundary in polygon.lines_iter() {
+        match line_intersection(line, boundary) {
+            Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                fractions.push(fraction(intersection));
+            }
+            Some(LineIntersection::Collinear { intersection }) => {
+                fractions.push(fraction(intersection.start));
+                fractions.push(fraction(intersection.end));
+            }
+            None => {}
+        }
+    }
+    fractions.retain(|x| (0.0..=1.0).contains(x));
+    fractions.sort_by(|a, b| a.total_cmp(b));
+    fractions.dedup();
+
+    // Each piece between crossings is either entirely inside or outside
+    let mut pieces = Vec::new();
+    for pair in fractions.windows(2) {
+        let piece = Line::new(line.start + delta * pair[0], line.start + delta * pair[1]);
+        let midpoint = piece.start + piece.delta() / 2.0;
+        if polygon.contains(&midpoint) {
+            pieces.push(piece);
+        }
+    }
+    pieces
+}
+
+fn polygons_per_polygon(
+    polygons: Vec<WeightedPolygon>,
+    zones: &HashMap<String, MultiPolygon<f64>>,
+    metric: Metric,
+) -> BTreeMap<String, Vec<OverlappingPolygon>> {
+    // Empty polygons have nothing to sample, and no envelope to index
+    let tree = RTree::bulk_load(
+        polygons
+            .into_iter()
+            .filter(|polygon| polygon.polygon.bounding_rect().is_some())
+            .collect(),
+    );
+
+    let mut output = BTreeMap::new();
+    for (key, zone) in zones {
+        let mut polygons_inside = Vec::new();
+        let bounds = zone.bounding_rect().unwrap();
+        let min = bounds.min();
+        let max = bounds.max();
+        let envelope: AABB<[f64; 2]> = AABB::from_corners([min.x, min.y], [max.x, max.y]);
+        for candidate in tree.locate_in_envelope_intersecting(&envelope) {
+            // Instead of clipping polygons crossing the boundary, points outside the zone are
+            // rejected when sampling. Only keep polygons that definitely overlap the zone, so
+            // that always terminates.
+            let crosses_boundary = if zone.contains(&candidate.polygon) {
+                false
+            } else if candidate
+                .polygon
+                .exterior()
+                .points()
+                .any(|pt| zone.contains(&pt))
+                || zone
+                    .exterior_coords_iter()
+                    .any(|pt| candidate.polygon.contains(&pt))
+            {
+                true
+            } else {
+                continue;
+            };
+            let weight = candidate.weight * metric.area(&candidate.polygon);
+            if weight > 0.0 {
+                if let Some(bounds) = candidate.polygon.bounding_rect() {
+                    polygons_inside.push(OverlappingPolygon {
+                        polygon: candidate.polygon.clone(),
+                        bounds,
+                        weight,
+                        crosses_boundary,
+                    });
+                }
+            }
+        }
+        output.insert(key.clone(), polygons_inside);
+    }
+    output
+}
+
+/// The part of a zone inside a rectangle, like a raster cell. Unlike geo's boolean operations,
+/// this can't panic.
+fn clip_to_rect(zone: &MultiPolygon<f64>, rect: Rect<f64>) -> MultiPolygon<f64> {
+    let mut polygons = Vec::new();
+    for polygon in zone {
+        let exterior = clip_ring(polygon.exterior(), rect);
+        if exterior.len() < 3 {
+            continue;
+        }
+        let interiors = polygon
+            .interiors()
+            .iter()
+            .map(|ring| clip_ring(ring, rect))
+            .filter(|ring| ring.len() >= 3)
+            .map(LineString::new)
+            .collect();
+        polygons.push(Polygon::new(LineString::new(exterior), interiors));
+    }
+    MultiPolygon::new(polygons)
+}
+
+/// Clips a ring to a rectangle with the Sutherland-Hodgman algorithm, which works for any ring
+/// because the rectangle is convex. If the ring leaves and re-enters the rectangle, the result
+/// runs back and forth along the rectangle's edges, which doesn't change its area or which
+/// points it contains. The result isn't closed.
+fn clip_ring(ring: &LineString<f64>, rect: Rect<f64>) -> Vec<Coord<f64>> {
+    let mut points = ring.0.clone();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let (min, max) = (rect.min(), rect.max());
+    // Clip against each edge of the rectangle in turn. Each edge is an axis, a value along it,
+    // and whether points above or below that value are inside.
+    for (is_x, value, keep_above) in [
+        (true, min.x, true),
+        (true, max.x, false),
+        (false, min.y, true),
+        (false, max.y, false),
+    ] {
+        let inside = |pt: Coord<f64>| {
+            let x = if is_x { pt.x } else { pt.y };
+            if keep_above {
+                x >= value
+            } else {
+                x <= value
+            }
+        };
+        // Only called when one end is inside and the other isn't, so the edge isn't parallel
+        let crossing = |a: Coord<f64>, b: Coord<f64>| {
+            if is_x {
+                let t = (value - a.x) / (b.x - a.x);
+                Coord {
+                    x: value,
+                    y: a.y + t * (b.y - a.y),
+                }
+            } else {
+                let t = (value - a.y) / (b.y - a.y);
+                Coord {
+                    x: a.x + t * (b.x - a.x),
+                    y: value,
+                }
+            }
+        };
+
+        let input = std::mem::take(&mut points);
+        for (idx, current) in input.iter().enumerate() {
+            let previous = input[(idx + input.len() - 1) % input.len()];
+            match (inside(previous), inside(*current)) {
+                (true, true) => points.push(*current),
+                (true, false) => points.push(crossing(previous, *current)),
+                (false, true) => {
+                    points.push(crossing(previous, *current));
+                    points.push(*current);
+                }
+                (false, false) => {}
+            }
+        }
+    }
+    points
+}
+
+fn cells_per_polygon(
+    raster: Raster,
+    zones: &HashMap<String, MultiPolygon<f64>>,
+) -> BTreeMap<String, Vec<RasterCell>> {
+    let mut output = BTreeMap::new();
+    for (key, zone) in zones {
+        let mut cells_inside = Vec::new();
+        // Cells not touching the boundary are entirely inside or outside the zone
+        let boundary: RTree<Line<f64>> = RTree::bulk_load(zone.lines_iter().collect());
+        let (rows, columns) = raster.cells_overlapping(zone.bounding_rect().unwrap());
+        for row in rows {
+            for column in columns.clone() {
+                let value = raster.get(row, column);
+                if value.is_nan() || value <= 0.0 {
+                    continue;
+                }
+                let bounds = raster.cell_bounds(row, column);
+                let envelope = AABB::from_corners(bounds.min().into(), bounds.max().into());
+                if boundary
+                    .locate_in_envelope_intersecting(&envelope)
+                    .next()
+                    .is_none()
+                {
+                    if zone.contains(&bounds.center()) {
+                        cells_inside.push(RasterCell {
+                            bounds,
+                            weight: value,
+                            clipped: None,
+                        });
+                    }
+                    continue;
+                }
+
+                // Weight the cell by how much of it overlaps the zone
+                let clipped = clip_to_rect(zone, bounds);
+                let fraction = clipped.unsigned_area() / bounds.unsigned_area();
+                if fraction > 0.0 {
+                    if let Some(bounds) = clipped.bounding_rect() {
+                        cells_inside.push(RasterCell {
+                            bounds,
+                            weight: value * fraction,
+                            clipped: Some(clipped),
+                        });
+                    }
+                }
+            }
+        }
+        output.insert(key.clone(), cells_inside);
+    }
+    output
+}
+
 fn to_geojson(pt1: Point<f64>, pt2: Point<f64>, properties: Map<String, Value>) -> Feature {
     let line_string: LineString<f64> = vec![pt1, pt2].into();
     Feature {
@@ -438,58 +1995,249 @@ fn to_geojson(pt1: Point<f64>, pt2: Point<f64>, properties: Map<String, Value>)
     }
 }
 
+/// Weighted items belonging to one zone, along with an alias table to sample from them in
+/// constant time. Building the table is linear in the number of items, but only happens once per
+/// zone, no matter how many OD rows reference it.
+struct WeightedItems<T> {
+    items: Vec<T>,
+    index: WeightedAliasIndex<f64>,
+    /// Covers every point that could be sampled from the items
+    bounds: Rect<f64>,
+}
+
+impl<T> WeightedItems<T> {
+    /// Builds a table for each zone. Zones without any items or with all weights 0 are omitted.
+    fn per_zone(
+        items_per_zone: BTreeMap<String, Vec<T>>,
+        weight: fn(&T) -> f64,
+        bounds: fn(&T) -> Rect<f64>,
+    ) -> Result<BTreeMap<String, WeightedItems<T>>> {
+        let mut output = BTreeMap::new();
+        for (zone_id, items) in items_per_zone {
+            match WeightedAliasIndex::new(items.iter().map(weight).collect()) {
+                Ok(index) => {
+                    // There's at least one item
+                    let bounds = items
+                        .iter()
+                        .map(bounds)
+                        .reduce(|a, b| {
+                            Rect::new(
+                                (a.min().x.min(b.min().x), a.min().y.min(b.min().y)),
+                                (a.max().x.max(b.max().x), a.max().y.max(b.max().y)),
+                            )
+                        })
+                        .unwrap();
+                    output.insert(
+                        zone_id,
+                        WeightedItems {
+                            items,
+                            index,
+                            bounds,
+                        },
+                    );
+                }
+                Err(WeightedError::NoItem) | Err(WeightedError::AllWeightsZero) => {}
+                Err(err) => bail!("Can't use subpoint weights in zone {zone_id}: {err}"),
+            }
+        }
+        Ok(output)
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> &T {
+        &self.items[self.index.sample(rng)]
+    }
+}
+
+/// The subpoints of every zone, calculated once upfront from a `Subsample`.
+enum SubpointsPerZone {
+    RandomPoints,
+    WeightedPoints(BTreeMap<String, WeightedItems<WeightedPoint>>),
+    WeightedLines(BTreeMap<String, WeightedItems<WeightedLine>>),
+    WeightedPolygons(BTreeMap<String, WeightedItems<OverlappingPolygon>>),
+    WeightedRaster(BTreeMap<String, WeightedItems<RasterCell>>),
+}
+
+impl SubpointsPerZone {
+    fn new(
+        subsample: Subsample,
+        zones: &HashMap<String, MultiPolygon<f64>>,
+        metric: Metric,
+    ) -> Result<SubpointsPerZone> {
+        Ok(match subsample {
+            Subsample::RandomPoints => SubpointsPerZone::RandomPoints,
+            Subsample::WeightedPoints(points) => {
+                SubpointsPerZone::WeightedPoints(WeightedItems::per_zone(
+                    points_per_polygon(points, zones),
+                    |pt| pt.weight,
+                    |pt| Rect::new(pt.point.0, pt.point.0),
+                )?)
+            }
+            Subsample::WeightedLines(lines) => {
+                SubpointsPerZone::WeightedLines(WeightedItems::per_zone(
+                    lines_per_polygon(lines, zones, metric),
+                    |l| l.weight,
+                    |l| l.line.bounding_rect(),
+                )?)
+            }
+            Subsample::WeightedPolygons(polygons) => {
+                SubpointsPerZone::WeightedPolygons(WeightedItems::per_zone(
+                    polygons_per_polygon(polygons, zones, metric),
+                    |p| p.weight,
+                    |p| p.bounds,
+                )?)
+            }
+            Subsample::WeightedRaster(raster) => {
+                let cells = WeightedItems::per_zone(
+                    cells_per_polygon(raster, zones),
+                    |c| c.weight,
+                    |c| c.bounds,
+                )?;
+                let mut empty: Vec<&String> =
+                    zones.keys().filter(|id| !cells.contains_key(*id)).collect();
+                if !empty.is_empty() {
+                    empty.sort();
+                    eprintln!(
+                        "Warning: {} zones don't overlap any raster cells with a positive value, so points will be sampled uniformly within them: {:?}",
+                        empty.len(),
+                        empty
+                    );
+                }
+                SubpointsPerZone::WeightedRaster(cells)
+            }
+        })
+    }
+}
+
 enum Subsampler<'a> {
     RandomPoints(&'a MultiPolygon<f64>, Rect<f64>),
-    WeightedPoints(&'a Vec<WeightedPoint>),
+    WeightedPoints(&'a WeightedItems<WeightedPoint>),
+    WeightedLines(&'a WeightedItems<WeightedLine>),
+    /// Also has the zone, to reject points outside it
+    WeightedPolygons(&'a WeightedItems<OverlappingPolygon>, &'a MultiPolygon<f64>),
+    WeightedRaster(&'a WeightedItems<RasterCell>),
+    /// A specific place, instead of a zone
+    Fixed(Point<f64>),
 }
 
 impl<'a> Subsampler<'a> {
     fn new(
-        points_per_zone: &'a Option<BTreeMap<String, Vec<WeightedPoint>>>,
+        points_per_zone: &'a SubpointsPerZone,
         zone_polygon: &'a MultiPolygon<f64>,
         zone_id: &str,
     ) -> Result<Subsampler<'a>> {
-        if let Some(points_per_zone) = points_per_zone {
-            if let Some(points) = points_per_zone.get(zone_id) {
-                if !points.is_empty() {
+        match points_per_zone {
+            SubpointsPerZone::RandomPoints => match zone_polygon.bounding_rect() {
+                Some(bounds) => Ok(Subsampler::RandomPoints(zone_polygon, bounds)),
+                None => bail!("can't calculate bounding box for zone {}", zone_id),
+            },
+            SubpointsPerZone::WeightedPoints(points_per_zone) => {
+                if let Some(points) = points_per_zone.get(zone_id) {
                     return Ok(Subsampler::WeightedPoints(points));
                 }
+                bail!("No subpoints for zone {}", zone_id);
             }
-            bail!("No subpoints for zone {}", zone_id);
-        } else {
-            match zone_polygon.bounding_rect() {
-                Some(bounds) => Ok(Subsampler::RandomPoints(zone_polygon, bounds)),
-                None => bail!("can't calculate bounding box for zone {}", zone_id),
+            SubpointsPerZone::WeightedLines(lines_per_zone) => {
+                if let Some(lines) = lines_per_zone.get(zone_id) {
+                    return Ok(Subsampler::WeightedLines(lines));
+                }
+                bail!("No subpoint lines for zone {}", zone_id);
+            }
+            SubpointsPerZone::WeightedPolygons(polygons_per_zone) => {
+                if let Some(polygons) = polygons_per_zone.get(zone_id) {
+                    return Ok(Subsampler::WeightedPolygons(polygons, zone_polygon));
+                }
+                bail!("No subpoint polygons for zone {}", zone_id);
+            }
+            SubpointsPerZone::WeightedRaster(cells_per_zone) => {
+                if let Some(cells) = cells_per_zone.get(zone_id) {
+                    return Ok(Subsampler::WeightedRaster(cells));
+                }
+                // SubpointsPerZone warns about this upfront
+                match zone_polygon.bounding_rect() {
+                    Some(bounds) => Ok(Subsampler::RandomPoints(zone_polygon, bounds)),
+                    None => bail!("can't calculate bounding box for zone {}", zone_id),
+                }
             }
         }
     }
 
-    fn sample(&self, rng: &mut StdRng) -> Point<f64> {
+    fn sample<R: Rng>(&self, rng: &mut R) -> Point<f64> {
         match self {
-            Subsampler::RandomPoints(polygon, bounds) => loop {
-                let x = rng.gen_range(bounds.min().x..=bounds.max().x);
-                let y = rng.gen_range(bounds.min().y..=bounds.max().y);
-                let pt = Point::new(x, y);
-                if polygon.contains(&pt) {
+            Subsampler::RandomPoints(polygon, bounds) => random_point_inside(*polygon, bounds, rng),
+            Subsampler::WeightedPoints(points) => {
+                // TODO Sample with replacement or not?
+                points.sample(rng).point
+            }
+            Subsampler::WeightedLines(lines) => {
+                // Segments are weighted by length, so picking one and then a uniform position
+                // along it is uniform along the whole network
+                let line = lines.sample(rng).line;
+                let fraction = rng.gen_range(0.0..=1.0);
+                Point::from(line.start + line.delta() * fraction)
+            }
+            Subsampler::WeightedPolygons(polygons, zone) => loop {
+                // Polygons are weighted by their whole area, so picking one and then a uniform
+                // point inside it is uniform over all of them. Rejecting points outside the zone
+                // keeps that true for the parts inside.
+                let polygon = polygons.sample(rng);
+                let pt = random_point_inside(&polygon.polygon, &polygon.bounds, rng);
+                if !polygon.crosses_boundary || zone.contains(&pt) {
                     return pt;
                 }
             },
-            Subsampler::WeightedPoints(points) => {
-                // TODO Sample with replacement or not?
-                // TODO If there are no two subpoints that're greater than this distance, we'll
-                // infinite loop. Detect upfront, or maybe just give up after a fixed number of
-                // attempts?
-                points.choose_weighted(rng, |pt| pt.weight).unwrap().point
+            Subsampler::WeightedRaster(cells) => {
+                let cell = cells.sample(rng);
+                match cell.clipped {
+                    Some(ref clipped) => random_point_inside(clipped, &cell.bounds, rng),
+                    None => Point::new(
+                        rng.gen_range(cell.bounds.min().x..=cell.bounds.max().x),
+                        rng.gen_range(cell.bounds.min().y..=cell.bounds.max().y),
+                    ),
+                }
             }
+            Subsampler::Fixed(point) => *point,
+        }
+    }
+
+    /// Covers every point that could be sampled.
+    fn bounds(&self) -> Rect<f64> {
+        match self {
+            Subsampler::RandomPoints(_, bounds) => *bounds,
+            Subsampler::WeightedPoints(points) => points.bounds,
+            Subsampler::WeightedLines(lines) => lines.bounds,
+            Subsampler::WeightedPolygons(polygons, _) => polygons.bounds,
+            Subsampler::WeightedRaster(cells) => cells.bounds,
+            Subsampler::Fixed(point) => Rect::new(point.0, point.0),
         }
     }
 
     /// No result for random points in a polygon (infinite, unless the polygon is extremely
-    /// degenerate). For weighted points, returns the number of them.
+    /// degenerate) or along lines. For weighted points, returns the number of them, and for a
+    /// fixed point, 1.
     fn num_points(&self) -> Option<usize> {
         match self {
-            Subsampler::RandomPoints(_, _) => None,
-            Subsampler::WeightedPoints(points) => Some(points.len()),
+            Subsampler::RandomPoints(_, _)
+            | Subsampler::WeightedLines(_)
+            | Subsampler::WeightedPolygons(_, _)
+            | Subsampler::WeightedRaster(_) => None,
+            Subsampler::WeightedPoints(points) => Some(points.items.len()),
+            Subsampler::Fixed(_) => Some(1),
+        }
+    }
+}
+
+/// Rejection sampling: pick points in the bounding box until one lands inside the shape.
+fn random_point_inside<G: Contains<Point<f64>>, R: Rng>(
+    shape: &G,
+    bounds: &Rect<f64>,
+    rng: &mut R,
+) -> Point<f64> {
+    loop {
+        let x = rng.gen_range(bounds.min().x..=bounds.max().x);
+        let y = rng.gen_range(bounds.min().y..=bounds.max().y);
+        let pt = Point::new(x, y);
+        if shape.contains(&pt) {
+            return pt;
         }
     }
 }
@@ -1,11 +1,17 @@
-use std::io::BufWriter;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
 use fs_err::File;
-use geojson::FeatureWriter;
+use odjitter::{
+    CsvWriter, FlatGeobufWriter, GeoJsonSeqWriter, GeoJsonWriter, GeoParquetWriter, Metadata,
+    Summary,
+};
 use rand::rngs::StdRng;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
 
 #[derive(Parser)]
 #[clap(about, version, author)]
@@ -37,50 +43,167 @@ enum Action {
     Disaggregate {
         #[clap(flatten)]
         common: CommonArgs,
+
+        /// How to turn fractional trip counts into a whole number of trips. `trs`
+        /// (truncate-replicate-sample) preserves the total number of trips per mode, but reads all
+        /// input into memory first.
+        #[clap(long, arg_enum, default_value = "truncate")]
+        integerisation: Integerisation,
+
+        /// Overrides `max_distance_meters` for one mode column, specified like `foot=2000`. This
+        /// can be repeated for different modes.
+        #[clap(long, parse(try_from_str = parse_mode_distance), multiple_occurrences = true)]
+        max_distance_meters_per_mode: Vec<(String, f64)>,
+    },
+    /// Replace each desire line with the shortest path along a road network. Desire lines whose
+    /// ends aren't connected by the network stay straight.
+    Route {
+        /// The path to a file with LineString desire lines, like the output of `jitter`, in any
+        /// format supported for zones
+        #[clap(long)]
+        input_path: String,
+        /// The path to a file with the road network's LineStrings, in any format supported for
+        /// zones. Roads are only connected where they share a vertex with exactly the same
+        /// coordinates.
+        #[clap(long)]
+        network_path: String,
+        /// If specified, this numeric property of each road multiplies its length to get the cost
+        /// of using it. Roads with a higher weight are avoided. By default, the shortest path is
+        /// used.
+        #[clap(long)]
+        weight_key: Option<String>,
+        /// The coordinate reference system of the desire lines and network, like `EPSG:27700` or a
+        /// proj4 string. By default, this is read from the network file, or assumed to be WGS84,
+        /// and the desire lines have to match. If this is specified, the files aren't checked.
+        #[clap(long)]
+        crs: Option<String>,
+
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    /// Sum the counts of LineStrings, like the output of `jitter` or `route`, onto segments that
+    /// don't overlap
+    Overline {
+        /// The path to a file with LineStrings, in any format supported for zones. LineStrings only
+        /// overlap where they share exactly the same vertices, like routes along the same network.
+        #[clap(long)]
+        input_path: String,
+        /// Which numeric property to sum. This can be repeated. By default, every property that
+        /// `jitter` would treat as a count is summed. Other properties are dropped.
+        #[clap(long, multiple_occurrences = true)]
+        sum_key: Vec<String>,
+        /// Which property names the origin zone. It's never summed.
+        #[clap(long, default_value = "geo_code1")]
+        origin_key: String,
+        /// Which property names the destination zone. It's never summed.
+        #[clap(long, default_value = "geo_code2")]
+        destination_key: String,
+        /// Which property, if any, has a distance per trip. It's never summed.
+        #[clap(long)]
+        distance_key: Option<String>,
+        /// The coordinate reference system of the LineStrings, like `EPSG:27700` or a proj4
+        /// string, which the output is in too. By default, this is read from the input file, or
+        /// assumed to be WGS84.
+        #[clap(long)]
+        crs: Option<String>,
+
+        #[clap(flatten)]
+        output: OutputArgs,
     },
 }
 
 #[derive(Clone, Parser)]
 struct CommonArgs {
-    /// The path to a CSV file with aggregated origin/destination data
+    /// The path to a file with aggregated origin/destination data. This is usually CSV, but
+    /// Parquet (`.parquet`) and GeoJSON features with just properties (`.geojson`) also work. Use
+    /// `-` to read CSV from stdin.
     #[clap(long)]
     od_csv_path: String,
 
-    /// The path to a GeoJSON file with named zones
+    /// The path to a file with named zones. GeoJSON, FlatGeobuf (`.fgb`), ESRI Shapefile (`.shp`)
+    /// and GeoPackage (`.gpkg`) are supported, detected from the file extension.
     #[clap(long)]
     zones_path: String,
-
-    /// The path to a file where the output will be written
+    /// The path to a file with named zones for destinations, in any format supported for
+    /// `zones_path`. If this isn't specified, `zones_path` is used for both origins and
+    /// destinations. Zone names in the two files may overlap.
     #[clap(long)]
-    output_path: String,
-
-    /// Output a FlatGeobuf file (without an index) if true, or a GeoJSON file by default
+    zones_destinations_path: Option<String>,
+    /// The path to a file with points, like schools or hospitals, in any format supported for
+    /// zones. Origins in the OD data matching the `point_id_key_origins` property of a point start
+    /// exactly there, instead of being jittered within a zone. Other origins use the zones.
     #[clap(long)]
-    output_fgb: bool,
+    points_origins_path: Option<String>,
+    /// In `points_origins_path`, which property is the ID of a point
+    #[clap(long, default_value = "id")]
+    point_id_key_origins: String,
+    /// The path to a file with points, like schools or hospitals, in any format supported for
+    /// zones. Destinations in the OD data matching the `point_id_key_destinations` property of a
+    /// point end exactly there, instead of being jittered within a zone. Other destinations use the
+    /// zones.
+    #[clap(long)]
+    points_destinations_path: Option<String>,
+    /// In `points_destinations_path`, which property is the ID of a point
+    #[clap(long, default_value = "id")]
+    point_id_key_destinations: String,
 
-    /// The path to a GeoJSON file to use for sampling subpoints for origin zones. If this isn't
-    /// specified, random points within each zone will be used instead.
+    #[clap(flatten)]
+    output: OutputArgs,
+
+    /// The path to a file to use for sampling subpoints for origin zones, in any format supported
+    /// for zones. If this isn't specified, random points within each zone will be used instead.
     #[clap(long)]
     subpoints_origins_path: Option<String>,
+    /// How to sample from the features in `subpoints_origins_path`.
+    #[clap(long, arg_enum, default_value = "vertices")]
+    subpoints_origins_sampling: Sampling,
+    /// The path to a single-band GeoTIFF file, such as gridded population, to use for sampling
+    /// points in origin zones. Cells are picked with probability proportional to their value, then
+    /// a random point within the cell is used.
+    #[clap(long, conflicts_with = "subpoints-origins-path")]
+    raster_origins_path: Option<String>,
     /// If specified, this column will be used to more frequently choose subpoints in
     /// `subpoints_origins_path` with a higher weight value. Otherwise all subpoints will be
     /// equally likely to be chosen.
     #[clap(long)]
     weight_key_origins: Option<String>,
 
-    /// The path to a GeoJSON file to use for sampling subpoints for destination zones. If this
-    /// isn't specified, random points within each zone will be used instead.
+    /// The path to a file to use for sampling subpoints for destination zones, in any format
+    /// supported for zones. If this isn't specified, random points within each zone will be used
+    /// instead.
     #[clap(long)]
     subpoints_destinations_path: Option<String>,
+    /// How to sample from the features in `subpoints_destinations_path`.
+    #[clap(long, arg_enum, default_value = "vertices")]
+    subpoints_destinations_sampling: Sampling,
+    /// The path to a single-band GeoTIFF file, such as gridded population, to use for sampling
+    /// points in destination zones. Cells are picked with probability proportional to their value,
+    /// then a random point within the cell is used.
+    #[clap(long, conflicts_with = "subpoints-destinations-path")]
+    raster_destinations_path: Option<String>,
     /// If specified, this column will be used to more frequently choose subpoints in
     /// `subpoints_destinations_path` with a higher weight value. Otherwise all subpoints will be
     /// equally likely to be chosen.
     #[clap(long)]
     weight_key_destinations: Option<String>,
 
-    /// In the zones GeoJSON file, which property is the name of a zone
+    /// In the zones file, which property is the name of a zone
     #[clap(long, default_value = "InterZone")]
     zone_name_key: String,
+    /// In the destination zones file, which property is the name of a zone. Defaults to
+    /// `zone_name_key`.
+    #[clap(long, requires = "zones-destinations-path")]
+    zone_name_key_destinations: Option<String>,
+    /// The coordinate reference system of the zones and subpoints, like `EPSG:27700` or a proj4
+    /// string. By default, this is read from the zones file, or assumed to be WGS84, and the other
+    /// inputs have to match. If this is specified, the files aren't checked, so this also overrides
+    /// a CRS that can't be detected. Distances are measured with the Haversine formula for
+    /// longitude and latitude, or in a straight line for projected coordinates.
+    #[clap(long)]
+    crs: Option<String>,
+    /// Transform the output from `crs` to WGS84.
+    #[clap(long)]
+    output_wgs84: bool,
     /// Which column in the OD row specifies the zone where trips originate?
     #[clap(long, default_value = "geo_code1")]
     origin_key: String,
@@ -89,17 +212,161 @@ struct CommonArgs {
     destination_key: String,
     /// By default, the output will be different every time the tool is run, based on a different
     /// random number generator seed. Specify this to get deterministic behavior, given the same
-    /// input.
+    /// input. The seed used is recorded in the output's metadata.
     #[clap(long)]
     rng_seed: Option<u64>,
+    /// Which random number generator algorithm to use. `chacha8` and `pcg64` produce the same
+    /// output for the same seed across versions of odjitter's dependencies. `std` may change
+    /// between releases of the `rand` crate.
+    #[clap(long, arg_enum, default_value = "chacha8")]
+    rng_algorithm: RngAlgorithm,
     /// Guarantee that jittered origin and destination points are at least this distance apart.
     #[clap(long, default_value = "1.0")]
     min_distance_meters: f64,
+    /// If specified, guarantee that jittered origin and destination points are at most this
+    /// distance apart.
+    #[clap(long)]
+    max_distance_meters: Option<f64>,
+    /// How many times to sample an origin and destination point satisfying the distance
+    /// constraints (and `deduplicate_pairs`) before giving up on one trip.
+    #[clap(long, default_value = "1000")]
+    max_attempts: usize,
+    /// What to do with a trip after `max_attempts` fail. With `error`, every row of the OD file is
+    /// checked before writing any output, to make sure the constraints aren't impossible. Rows
+    /// from stdin can only be read once, so they're checked as they're processed. `accept-closest`
+    /// uses the pair that came closest to satisfying the distance constraints, and `drop` leaves
+    /// out the trip.
+    #[clap(long, arg_enum, default_value = "error")]
+    retry_fallback: RetryFallback,
+    /// If specified, this column in the OD row has the observed mean distance of its trips, in
+    /// meters. Trips are sampled to approximately match it, by picking the pair of points with the
+    /// closest distance out of `distance_candidates` pairs. Rows with an empty value are sampled
+    /// normally. A target that can't be met isn't an error; the closest pairs found are used. A
+    /// summary of the achieved distances is printed at the end.
+    #[clap(long)]
+    distance_key: Option<String>,
+    /// How many pairs of points to consider per trip when matching `distance_key`. More
+    /// candidates match the distances more closely, but take longer.
+    #[clap(long, default_value = "20")]
+    distance_candidates: usize,
     /// Prevent duplicate (origin, destination) pairs from appearing in the output. This may
     /// increase memory and runtime requirements. Note the duplication uses the floating point
     /// precision of the input data, and only consider geometry (not any properties).
     #[clap(long)]
     deduplicate_pairs: bool,
+    /// What to do with OD rows referencing a zone that isn't in the zones file. `skip` prints a
+    /// warning, and `reject` also writes the rows to `rejects_csv_path`. A summary of the unknown
+    /// zones is printed at the end.
+    #[clap(long, arg_enum, default_value = "fail")]
+    unknown_zones: UnknownZones,
+    /// With `--unknown-zones reject`, the path to a CSV file where rows referencing unknown zones
+    /// will be written.
+    #[clap(long, required_if_eq("unknown-zones", "reject"))]
+    rejects_csv_path: Option<String>,
+}
+
+#[derive(Clone, Parser)]
+struct OutputArgs {
+    /// The path to a file where the output will be written, or `-` to write to stdout. Progress
+    /// messages are always written to stderr.
+    #[clap(long)]
+    output_path: String,
+
+    /// The format of the output file
+    #[clap(long, arg_enum, default_value = "geojson")]
+    output_format: OutputFormat,
+    /// Output a FlatGeobuf file. This is the same as `--output-format fgb`.
+    #[clap(long, conflicts_with = "output-format")]
+    output_fgb: bool,
+    /// For FlatGeobuf output, write a spatial index, so the file can be used for bounding box
+    /// queries, like HTTP range requests from web maps. Features are sorted spatially instead of
+    /// staying in the input order. Building the index needs roughly 100 extra bytes of memory per
+    /// output feature.
+    #[clap(long)]
+    fgb_index: bool,
+    /// For CSV output, how to write each desire line
+    #[clap(long, arg_enum, default_value = "coordinates")]
+    csv_geometry: CsvGeometry,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ArgEnum)]
+enum OutputFormat {
+    /// A GeoJSON FeatureCollection
+    Geojson,
+    /// FlatGeobuf, without an index unless `--fgb-index` is specified
+    Fgb,
+    /// GeoParquet, with WKB geometry and a typed column per property. Column types are inferred
+    /// from the first 10,000 features. The output has to be in WGS84, so with a projected `crs`,
+    /// `--output-wgs84` is needed.
+    Geoparquet,
+    /// A CSV file with the geometry followed by the input columns. The origin and destination
+    /// columns come first, then the rest in the same order as the input.
+    Csv,
+    /// An RFC 8142 GeoJSON text sequence, with one feature per line, each preceded by a record
+    /// separator
+    Geojsonseq,
+    /// Newline-delimited GeoJSON, with one feature per line
+    Ndjson,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum CsvGeometry {
+    /// `o_lon`, `o_lat`, `d_lon` and `d_lat` columns, or `o_x`, `o_y`, `d_x` and `d_y` for
+    /// projected output
+    Coordinates,
+    /// A `geometry` column in WKT
+    Wkt,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum Sampling {
+    /// Use every vertex of every geometry as a subpoint
+    Vertices,
+    /// Pick positions uniformly along LineStrings, so longer lines are more likely. Other
+    /// geometry types are ignored.
+    Lines,
+    /// Pick points uniformly inside polygons, so larger polygons are more likely. Other geometry
+    /// types are ignored.
+    Polygons,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum Integerisation {
+    Truncate,
+    Round,
+    Stochastic,
+    Trs,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum UnknownZones {
+    Fail,
+    Skip,
+    Reject,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum RetryFallback {
+    Error,
+    AcceptClosest,
+    Drop,
+}
+
+#[derive(Clone, Copy, clap::ArgEnum)]
+enum RngAlgorithm {
+    Chacha8,
+    Pcg64,
+    Std,
+}
+
+impl RngAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            RngAlgorithm::Chacha8 => "chacha8",
+            RngAlgorithm::Pcg64 => "pcg64",
+            RngAlgorithm::Std => "std",
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -108,66 +375,268 @@ fn main() -> Result<()> {
     let common = match args.action {
         Action::Jitter { ref common, .. } => common.clone(),
         Action::Disaggregate { ref common, .. } => common.clone(),
+        Action::Route {
+            input_path,
+            network_path,
+            weight_key,
+            crs,
+            output,
+        } => return route(input_path, network_path, weight_key, crs, output),
+        Action::Overline {
+            input_path,
+            sum_key,
+            origin_key,
+            destination_key,
+            distance_key,
+            crs,
+            output,
+        } => {
+            let options = odjitter::OverlineOptions {
+                sum_keys: sum_key,
+                origin_key,
+                destination_key,
+                distance_key,
+            };
+            return overline(input_path, options, crs, output);
+        }
     };
-    let output_path = common.output_path.clone();
-
-    if common.output_fgb {
-        let mut fgb = flatgeobuf::FgbWriter::create_with_options(
-            "odjitter",
-            flatgeobuf::GeometryType::LineString,
-            flatgeobuf::FgbWriterOptions {
-                write_index: false,
-                ..Default::default()
-            },
-        )?;
-        let write_feature = |feature| {
-            // TODO Is there a cheaper way to make a GeozeroDatasource, or something else we should
-            // generate from the API?
-            fgb.add_feature(geozero::geojson::GeoJson(&serde_json::to_string(&feature)?))?;
-            Ok(())
+    let output = common.output.clone();
+
+    // Always pick a seed upfront, so it can be recorded in the output
+    let metadata = Metadata {
+        rng_algorithm: common.rng_algorithm.name().to_string(),
+        rng_seed: common.rng_seed.unwrap_or_else(|| rand::thread_rng().gen()),
+    };
+    eprintln!(
+        "Using the {} RNG with seed {}",
+        metadata.rng_algorithm, metadata.rng_seed
+    );
+
+    let crs = input_crs(&common)?;
+    let output_crs = if common.output_wgs84 {
+        odjitter::Crs::wgs84()
+    } else {
+        crs.clone()
+    };
+
+    write_output(&output, Some(&metadata), &output_crs, |write_feature| {
+        run(args, common, crs, metadata.rng_seed, write_feature)?;
+        Ok(())
+    })
+}
+
+/// Sets up the writer for the chosen output format, then calls `produce` with a callback to write
+/// each feature. `crs` is what the output coordinates are in.
+fn write_output(
+    args: &OutputArgs,
+    metadata: Option<&Metadata>,
+    crs: &odjitter::Crs,
+    produce: impl FnOnce(&mut dyn FnMut(geojson::Feature) -> Result<()>) -> Result<()>,
+) -> Result<()> {
+    if let Err(err) = write_features(args, metadata, crs, produce) {
+        // Don't leave a truncated file behind
+        if args.output_path != "-" {
+            let _ = fs_err::remove_file(&args.output_path);
+        }
+        return Err(err);
+    }
+    if args.output_path != "-" {
+        eprintln!("Wrote {}", args.output_path);
+    }
+    Ok(())
+}
+
+fn write_features(
+    args: &OutputArgs,
+    metadata: Option<&Metadata>,
+    crs: &odjitter::Crs,
+    produce: impl FnOnce(&mut dyn FnMut(geojson::Feature) -> Result<()>) -> Result<()>,
+) -> Result<()> {
+    let output_format = if args.output_fgb {
+        OutputFormat::Fgb
+    } else {
+        args.output_format
+    };
+    let mut output = create_output(&args.output_path)?;
+    if output_format == OutputFormat::Fgb {
+        let mut writer = FlatGeobufWriter::new(metadata, args.fgb_index, crs)?;
+        produce(&mut |feature| writer.write_feature(&feature))?;
+        writer.finish(&mut output)?;
+    } else if output_format == OutputFormat::Geoparquet {
+        let mut writer = GeoParquetWriter::new(output, metadata, crs)?;
+        produce(&mut |feature| writer.write_feature(&feature))?;
+        writer.finish()?;
+    } else if output_format == OutputFormat::Csv {
+        let geometry = match args.csv_geometry {
+            CsvGeometry::Coordinates => odjitter::CsvGeometry::Coordinates,
+            CsvGeometry::Wkt => odjitter::CsvGeometry::Wkt,
         };
-        run(args, common, write_feature)?;
-        println!("Writing {output_path}");
-        let mut file = std::io::BufWriter::new(File::create(&output_path)?);
-        fgb.write(&mut file)?;
+        let mut writer = CsvWriter::new(output, geometry, crs);
+        produce(&mut |feature| writer.write_feature(&feature))?;
+        writer.finish()?;
+    } else if output_format == OutputFormat::Geojsonseq || output_format == OutputFormat::Ndjson {
+        let mut writer = GeoJsonSeqWriter::new(output, output_format == OutputFormat::Geojsonseq);
+        produce(&mut |feature| writer.write_feature(&feature))?;
+        writer.finish()?;
     } else {
-        // Write GeoJSON to a file. Instead of collecting the whole FeatureCollection in memory, write
-        // each feature as we get it.
-        let mut writer = FeatureWriter::from_writer(BufWriter::new(File::create(&output_path)?));
-        let write_feature = |feature| {
-            writer.write_feature(&feature)?;
-            Ok(())
+        // Write a GeoJSON FeatureCollection. Instead of collecting it all in memory, write each
+        // feature as we get it.
+        let mut writer = GeoJsonWriter::new(output, metadata)?;
+        produce(&mut |feature| writer.write_feature(&feature))?;
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+fn route(
+    input_path: String,
+    network_path: String,
+    weight_key: Option<String>,
+    crs: Option<String>,
+    output: OutputArgs,
+) -> Result<()> {
+    let crs = if let Some(ref crs) = crs {
+        // Don't detect the CRS of the files, so this overrides one that can't be detected
+        odjitter::Crs::parse(crs)?
+    } else {
+        let crs = if let Some(crs) = odjitter::read_crs(&network_path)? {
+            eprintln!("Using {} from {network_path}", crs.name());
+            crs
+        } else {
+            odjitter::Crs::wgs84()
         };
+        if let Some(input_crs) = odjitter::read_crs(&input_path)? {
+            if input_crs != crs {
+                bail!(
+                    "{input_path} uses {}, but the network uses {}; reproject it first",
+                    input_crs.name(),
+                    crs.name()
+                );
+            }
+        }
+        crs
+    };
 
-        run(args, common, write_feature)?;
+    let mut network = odjitter::RoadNetwork::load(&network_path, weight_key, &crs)?;
+    let features = odjitter::read_features(&input_path)?;
+    let mut total = 0;
+    let mut straight = 0;
+    write_output(&output, None, &crs, |write_feature| {
+        for feature in features {
+            let mut feature = feature?;
+            total += 1;
+            if !network.route_feature(&mut feature)? {
+                straight += 1;
+            }
+            write_feature(feature)?;
+        }
+        Ok(())
+    })?;
+    if straight > 0 {
+        eprintln!(
+            "{straight} of {total} desire lines aren't connected by the network, so they stay straight"
+        );
     }
-
-    println!("Wrote {output_path}");
     Ok(())
 }
 
+/// Opens a file for writing, or stdout for `-`.
+fn create_output(path: &str) -> Result<Box<dyn Write + Send>> {
+    if path == "-" {
+        Ok(Box::new(BufWriter::new(std::io::stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+/// Decides the CRS of the zones and subpoints. Unless it's specified, this makes sure every input
+/// uses it.
+fn input_crs(common: &CommonArgs) -> Result<odjitter::Crs> {
+    if let Some(ref crs) = common.crs {
+        // Don't detect the CRS of the files, so this overrides one that can't be detected
+        return odjitter::Crs::parse(crs);
+    }
+    let crs = if let Some(crs) = odjitter::read_crs(&common.zones_path)? {
+        eprintln!("Using {} from {}", crs.name(), common.zones_path);
+        crs
+    } else {
+        odjitter::Crs::wgs84()
+    };
+    for path in [
+        &common.zones_destinations_path,
+        &common.points_origins_path,
+        &common.points_destinations_path,
+        &common.subpoints_origins_path,
+        &common.subpoints_destinations_path,
+        &common.raster_origins_path,
+        &common.raster_destinations_path,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if let Some(subpoints_crs) = odjitter::read_crs(path)? {
+            if subpoints_crs != crs {
+                bail!(
+                    "{path} uses {}, but the zones use {}; reproject it first",
+                    subpoints_crs.name(),
+                    crs.name()
+                );
+            }
+        }
+    }
+    Ok(crs)
+}
+
 fn run<F: FnMut(geojson::Feature) -> Result<()>>(
     args: Args,
     common: CommonArgs,
+    crs: odjitter::Crs,
+    rng_seed: u64,
     write_feature: F,
-) -> Result<()> {
+) -> Result<Summary> {
     let zones = odjitter::load_zones(&common.zones_path, &common.zone_name_key)?;
-    println!("Scraped {} zones from {}", zones.len(), common.zones_path);
-
-    let subsample_origin = if let Some(ref path) = common.subpoints_origins_path {
-        let subpoints = odjitter::scrape_points(path, common.weight_key_origins)?;
-        println!("Scraped {} subpoints from {}", subpoints.len(), path);
-        odjitter::Subsample::WeightedPoints(subpoints)
+    eprintln!("Scraped {} zones from {}", zones.len(), common.zones_path);
+    let destination_zones = if let Some(ref path) = common.zones_destinations_path {
+        let name_key = common
+            .zone_name_key_destinations
+            .as_ref()
+            .unwrap_or(&common.zone_name_key);
+        let zones = odjitter::load_zones(path, name_key)?;
+        eprintln!("Scraped {} destination zones from {path}", zones.len());
+        Some(zones)
     } else {
-        odjitter::Subsample::RandomPoints
+        None
     };
-    let subsample_destination = if let Some(ref path) = common.subpoints_destinations_path {
-        let subpoints = odjitter::scrape_points(path, common.weight_key_destinations)?;
-        println!("Scraped {} subpoints from {}", subpoints.len(), path);
-        odjitter::Subsample::WeightedPoints(subpoints)
-    } else {
-        odjitter::Subsample::RandomPoints
+    let load_points = |path: &Option<String>, id_key: &str| -> Result<_> {
+        if let Some(path) = path {
+            let points = odjitter::load_points(path, id_key)?;
+            eprintln!("Scraped {} points from {path}", points.len());
+            Ok(Some(points))
+        } else {
+            Ok(None)
+        }
     };
+    let origin_points = load_points(&common.points_origins_path, &common.point_id_key_origins)?;
+    let destination_points = load_points(
+        &common.points_destinations_path,
+        &common.point_id_key_destinations,
+    )?;
+    let mut zones = odjitter::Zones::new(&zones, destination_zones.as_ref().unwrap_or(&zones));
+    zones.origin_points = origin_points.as_ref();
+    zones.destination_points = destination_points.as_ref();
+
+    let subsample_origin = load_subsample(
+        &common.subpoints_origins_path,
+        &common.raster_origins_path,
+        common.weight_key_origins,
+        common.subpoints_origins_sampling,
+    )?;
+    let subsample_destination = load_subsample(
+        &common.subpoints_destinations_path,
+        &common.raster_destinations_path,
+        common.weight_key_destinations,
+        common.subpoints_destinations_sampling,
+    )?;
 
     let options = odjitter::Options {
         subsample_origin,
@@ -175,33 +644,201 @@ fn run<F: FnMut(geojson::Feature) -> Result<()>>(
         origin_key: common.origin_key,
         destination_key: common.destination_key,
         min_distance_meters: common.min_distance_meters,
+        max_distance_meters: common.max_distance_meters,
+        max_distance_meters_per_mode: match args.action {
+            Action::Disaggregate {
+                ref max_distance_meters_per_mode,
+                ..
+            } => max_distance_meters_per_mode.iter().cloned().collect(),
+            _ => HashMap::new(),
+        },
         deduplicate_pairs: common.deduplicate_pairs,
+        integerisation: match args.action {
+            Action::Disaggregate { integerisation, .. } => match integerisation {
+                Integerisation::Truncate => odjitter::Integerisation::Truncate,
+                Integerisation::Round => odjitter::Integerisation::Round,
+                Integerisation::Stochastic => odjitter::Integerisation::Stochastic,
+                Integerisation::Trs => odjitter::Integerisation::TruncateReplicateSample,
+            },
+            _ => odjitter::Integerisation::Truncate,
+        },
+        unknown_zones: match common.unknown_zones {
+            UnknownZones::Fail => odjitter::UnknownZones::Fail,
+            UnknownZones::Skip => odjitter::UnknownZones::Skip,
+            // clap makes sure the path is set
+            UnknownZones::Reject => {
+                odjitter::UnknownZones::Reject(common.rejects_csv_path.unwrap().into())
+            }
+        },
+        max_attempts: common.max_attempts,
+        retry_fallback: match common.retry_fallback {
+            RetryFallback::Error => odjitter::RetryFallback::Error,
+            RetryFallback::AcceptClosest => odjitter::RetryFallback::AcceptClosest,
+            RetryFallback::Drop => odjitter::RetryFallback::Drop,
+        },
+        distance_key: common.distance_key,
+        distance_candidates: common.distance_candidates,
+        crs,
+        reproject_to_wgs84: common.output_wgs84,
     };
-    let mut rng = if let Some(seed) = common.rng_seed {
-        StdRng::seed_from_u64(seed)
-    } else {
-        StdRng::from_entropy()
-    };
+    match common.rng_algorithm {
+        RngAlgorithm::Chacha8 => run_with_rng(
+            args.action,
+            common.od_csv_path,
+            zones,
+            options,
+            ChaCha8Rng::seed_from_u64(rng_seed),
+            write_feature,
+        ),
+        RngAlgorithm::Pcg64 => run_with_rng(
+            args.action,
+            common.od_csv_path,
+            zones,
+            options,
+            Pcg64::seed_from_u64(rng_seed),
+            write_feature,
+        ),
+        RngAlgorithm::Std => run_with_rng(
+            args.action,
+            common.od_csv_path,
+            zones,
+            options,
+            StdRng::seed_from_u64(rng_seed),
+            write_feature,
+        ),
+    }
+}
 
-    match args.action {
+fn run_with_rng<R: Rng + SeedableRng + Send, F: FnMut(geojson::Feature) -> Result<()>>(
+    action: Action,
+    od_csv_path: String,
+    zones: odjitter::Zones,
+    options: odjitter::Options,
+    mut rng: R,
+    write_feature: F,
+) -> Result<Summary> {
+    let summary = match action {
         Action::Jitter {
             disaggregation_threshold,
             disaggregation_key,
             ..
-        } => {
-            odjitter::jitter(
-                common.od_csv_path,
-                &zones,
-                disaggregation_threshold,
-                disaggregation_key,
-                &mut rng,
-                options,
-                write_feature,
-            )?;
-        }
+        } => odjitter::jitter(
+            od_csv_path,
+            zones,
+            disaggregation_threshold,
+            disaggregation_key,
+            &mut rng,
+            options,
+            write_feature,
+        )?,
         Action::Disaggregate { .. } => {
-            odjitter::disaggregate(common.od_csv_path, &zones, &mut rng, options, write_feature)?;
+            odjitter::disaggregate(od_csv_path, zones, &mut rng, options, write_feature)?
+        }
+        Action::Route { .. } | Action::Overline { .. } => unreachable!(),
+    };
+    if summary.rows_skipped > 0 {
+        eprintln!(
+            "Skipped {} rows with {} trips, referencing {} unknown zones:",
+            summary.rows_skipped,
+            summary.trips_skipped,
+            summary.unknown_zones.len()
+        );
+        for (zone, trips) in &summary.unknown_zones {
+            eprintln!("  {zone}: {trips} trips");
+        }
+    }
+    if summary.trips_dropped > 0 {
+        eprintln!(
+            "Dropped {} trips without a pair of points satisfying the constraints",
+            summary.trips_dropped
+        );
+    }
+    if summary.trips_violating_constraints > 0 {
+        eprintln!(
+            "{} trips don't satisfy the distance constraints",
+            summary.trips_violating_constraints
+        );
+    }
+    if let Some(ref distances) = summary.distances {
+        eprintln!(
+            "For {} trips with a target distance, the mean target is {:.0}m and the mean achieved distance is {:.0}m, with a mean absolute error of {:.0}m",
+            distances.trips,
+            distances.mean_target_meters,
+            distances.mean_achieved_meters,
+            distances.mean_absolute_error_meters
+        );
+    }
+    Ok(summary)
+}
+
+fn overline(
+    input_path: String,
+    options: odjitter::OverlineOptions,
+    crs: Option<String>,
+    output: OutputArgs,
+) -> Result<()> {
+    let crs = if let Some(ref crs) = crs {
+        odjitter::Crs::parse(crs)?
+    } else {
+        odjitter::read_crs(&input_path)?.unwrap_or_else(odjitter::Crs::wgs84)
+    };
+    let mut total = 0;
+    let features = odjitter::read_features(&input_path)?.inspect(|_| total += 1);
+    let segments = odjitter::overline(features, &options)?;
+    eprintln!(
+        "Aggregated {total} LineStrings into {} segments",
+        segments.len()
+    );
+    write_output(&output, None, &crs, |write_feature| {
+        for feature in segments {
+            write_feature(feature)?;
+        }
+        Ok(())
+    })
+}
+
+fn parse_mode_distance(value: &str) -> Result<(String, f64)> {
+    if let Some((mode, distance)) = value.split_once('=') {
+        Ok((mode.to_string(), distance.parse()?))
+    } else {
+        bail!("{value} should be specified like mode=meters");
+    }
+}
+
+fn load_subsample(
+    path: &Option<String>,
+    raster_path: &Option<String>,
+    weight_key: Option<String>,
+    sampling: Sampling,
+) -> Result<odjitter::Subsample> {
+    if let Some(path) = raster_path {
+        let raster = odjitter::load_geotiff(path)?;
+        eprintln!(
+            "Loaded a {}x{} raster from {}",
+            raster.columns, raster.rows, path
+        );
+        return Ok(odjitter::Subsample::WeightedRaster(raster));
+    }
+    let path = if let Some(path) = path {
+        path
+    } else {
+        return Ok(odjitter::Subsample::RandomPoints);
+    };
+    match sampling {
+        Sampling::Vertices => {
+            let subpoints = odjitter::scrape_points(path, weight_key)?;
+            eprintln!("Scraped {} subpoints from {}", subpoints.len(), path);
+            Ok(odjitter::Subsample::WeightedPoints(subpoints))
+        }
+        Sampling::Lines => {
+            let lines = odjitter::scrape_lines(path, weight_key)?;
+            eprintln!("Scraped {} LineStrings from {}", lines.len(), path);
+            Ok(odjitter::Subsample::WeightedLines(lines))
+        }
+        Sampling::Polygons => {
+            let polygons = odjitter::scrape_polygons(path, weight_key)?;
+            eprintln!("Scraped {} polygons from {}", polygons.len(), path);
+            Ok(odjitter::Subsample::WeightedPolygons(polygons))
         }
     }
-    Ok(())
 }
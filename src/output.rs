@@ -0,0 +1,578 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use arrow_array::builder::{BinaryBuilder, BooleanBuilder, Float64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema};
+use flatgeobuf::{FgbCrs, FgbWriter, FgbWriterOptions, GeometryType};
+use geo_types::{Coord, Geometry, LineString};
+use geojson::Feature;
+use geozero::geojson::GeoJson;
+use geozero::{ColumnValue, CoordDimensions, PropertyProcessor, ToWkb};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use serde_json::{json, Value};
+
+use crate::Crs;
+
+/// Describes how some output was produced, so that it can be reproduced later.
+#[derive(Clone)]
+pub struct Metadata {
+    /// The name of the random number generator algorithm
+    pub rng_algorithm: String,
+    pub rng_seed: u64,
+}
+
+impl Metadata {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rng_algorithm": self.rng_algorithm,
+            "rng_seed": self.rng_seed,
+        })
+    }
+}
+
+/// Writes a GeoJSON FeatureCollection one feature at a time, instead of collecting everything in
+/// memory first. The metadata, if any, is recorded in an `odjitter` foreign member of the
+/// collection.
+pub struct GeoJsonWriter<W: Write> {
+    writer: W,
+    num_features: usize,
+}
+
+impl<W: Write> GeoJsonWriter<W> {
+    pub fn new(mut writer: W, metadata: Option<&Metadata>) -> Result<Self> {
+        write!(writer, "{{\"type\":\"FeatureCollection\",")?;
+        if let Some(metadata) = metadata {
+            write!(writer, "\"odjitter\":{},", metadata.to_json())?;
+        }
+        write!(writer, "\"features\":[")?;
+        Ok(Self {
+            writer,
+            num_features: 0,
+        })
+    }
+
+    pub fn write_feature(&mut self, feature: &Feature) -> Result<()> {
+        if self.num_features > 0 {
+            self.writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.writer, feature)?;
+        self.num_features += 1;
+        Ok(())
+    }
+
+    /// Closes the FeatureCollection. This must be called after the last feature.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.write_all(b"]}")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Writes one GeoJSON Feature per line, so output can be streamed, split or appended to. With
+/// `record_separator`, each feature is preceded by an ASCII record separator, making it an RFC
+/// 8142 GeoJSON text sequence. Without, it's newline-delimited GeoJSON. Either way, there's
+/// nowhere to record the metadata, so it's not written.
+pub struct GeoJsonSeqWriter<W: Write> {
+    writer: W,
+    record_separator: bool,
+}
+
+impl<W: Write> GeoJsonSeqWriter<W> {
+    pub fn new(writer: W, record_separator: bool) -> Self {
+        Self {
+            writer,
+            record_separator,
+        }
+    }
+
+    pub fn write_feature(&mut self, feature: &Feature) -> Result<()> {
+        if self.record_separator {
+            self.writer.write_all(b"\x1e")?;
+        }
+        serde_json::to_writer(&mut self.writer, feature)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Flushes all output. This must be called after the last feature.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Writes features with LineString geometry to a FlatGeobuf file. Features are buffered in a
+/// temporary file, then written out by `finish`. The metadata, if any, is recorded in the header's
+/// `metadata` field, and the CRS in its `crs` field. A CRS defined by a proj4 string has no EPSG
+/// code, so only its description is recorded.
+///
+/// FlatGeobuf declares property columns in the header, so they're taken from the first feature.
+/// Numbers become doubles, strings become strings, booleans become booleans, and anything else is
+/// stored as JSON. Later features with other properties or a different type of value cause an
+/// error. Missing properties are null.
+///
+/// With `write_index`, a packed Hilbert R-tree index is also written, so readers can efficiently
+/// load just the features in a bounding box, such as with HTTP range requests from a web map. The
+/// features are then sorted along a Hilbert curve, instead of staying in the order they were
+/// written. The trade-off is memory: without an index, about 56 bytes per feature are kept in
+/// memory until `finish`, and building the index needs roughly another 100 bytes per feature.
+pub struct FlatGeobufWriter<'a> {
+    fgb: FgbWriter<'a>,
+    /// Decided by the first feature
+    columns: Option<Vec<(String, flatgeobuf::ColumnType)>>,
+}
+
+impl<'a> FlatGeobufWriter<'a> {
+    /// `crs` is what the coordinates of the features are in.
+    pub fn new(metadata: Option<&Metadata>, write_index: bool, crs: &Crs) -> Result<Self> {
+        let metadata_json = metadata.map(|metadata| metadata.to_json().to_string());
+        let fgb_crs = match crs.epsg_code() {
+            Some(code) => FgbCrs {
+                code: code as i32,
+                ..Default::default()
+            },
+            None => FgbCrs {
+                description: Some(crs.name()),
+                ..Default::default()
+            },
+        };
+        let fgb = FgbWriter::create_with_options(
+            "odjitter",
+            GeometryType::LineString,
+            FgbWriterOptions {
+                write_index,
+                crs: fgb_crs,
+                metadata: metadata_json.as_deref(),
+                ..Default::default()
+            },
+        )?;
+        Ok(Self { fgb, columns: None })
+    }
+
+    pub fn write_feature(&mut self, feature: &Feature) -> Result<()> {
+        let properties = feature.properties.clone().unwrap_or_default();
+        let columns = self.columns.get_or_insert_with(|| {
+            let mut columns = Vec::new();
+            for (key, value) in &properties {
+                let column_type = match value {
+                    Value::Number(_) => flatgeobuf::ColumnType::Double,
+                    Value::String(_) => flatgeobuf::ColumnType::String,
+                    Value::Bool(_) => flatgeobuf::ColumnType::Bool,
+                    _ => flatgeobuf::ColumnType::Json,
+                };
+                self.fgb.add_column(key, column_type, |_, _| {});
+                columns.push((key.clone(), column_type));
+            }
+            columns
+        });
+        if let Some(key) = properties
+            .keys()
+            .find(|key| !columns.iter().any(|(k, _)| k == *key))
+        {
+            bail!("A feature has a {key} property, but the first feature didn't");
+        }
+
+        let geometry = if let Some(ref geometry) = feature.geometry {
+            serde_json::to_string(geometry)?
+        } else {
+            bail!("FlatGeobuf output needs every feature to have a geometry");
+        };
+        let mut result = Ok(());
+        self.fgb.add_feature_geom(GeoJson(&geometry), |writer| {
+            result = (|| {
+                for (idx, (key, column_type)) in columns.iter().enumerate() {
+                    let json;
+                    let value = match (column_type, properties.get(key)) {
+                        (_, None) | (_, Some(Value::Null)) => continue,
+                        (&flatgeobuf::ColumnType::Double, Some(Value::Number(x))) => {
+                            ColumnValue::Double(x.as_f64().unwrap())
+                        }
+                        (&flatgeobuf::ColumnType::String, Some(Value::String(x))) => {
+                            ColumnValue::String(x)
+                        }
+                        (&flatgeobuf::ColumnType::Bool, Some(Value::Bool(x))) => {
+                            ColumnValue::Bool(*x)
+                        }
+                        (&flatgeobuf::ColumnType::Json, Some(x)) => {
+                            json = x.to_string();
+                            ColumnValue::Json(&json)
+                        }
+                        (_, Some(x)) => bail!(
+                            "The {key} column has type {column_type:?} based on the first feature, but a later feature has {x}"
+                        ),
+                    };
+                    writer.property(idx, key, &value)?;
+                }
+                Ok(())
+            })();
+        })?;
+        result
+    }
+
+    /// Writes the whole file. This must be called after the last feature.
+    pub fn finish<W: Write>(self, writer: &'a mut W) -> Result<()> {
+        self.fgb.write(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// How `CsvWriter` represents each LineString.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsvGeometry {
+    /// `o_lon`, `o_lat`, `d_lon` and `d_lat` columns, from the first and last points. In a
+    /// projected CRS, they're `o_x`, `o_y`, `d_x` and `d_y` instead.
+    Coordinates,
+    /// A `geometry` column in WKT
+    Wkt,
+}
+
+/// Writes features with LineString geometry to a CSV file, with the geometry columns followed by
+/// one column per property.
+///
+/// The property columns are taken from the first feature, in the same order. For `jitter`, that's
+/// the origin and destination columns, then the rest in the order of the input CSV's header.
+/// Later features with other properties cause an error, and missing properties are left empty.
+/// Numbers and strings are written as they are, and anything else as JSON. CSV has nowhere to
+/// record the metadata, so it's not written.
+pub struct CsvWriter<W: Write> {
+    writer: csv::Writer<W>,
+    geometry: CsvGeometry,
+    /// Are the coordinates longitude and latitude?
+    geographic: bool,
+    /// Decided by the first feature
+    columns: Option<Vec<String>>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// `crs` is what the coordinates of the features are in.
+    pub fn new(writer: W, geometry: CsvGeometry, crs: &Crs) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+            geometry,
+            geographic: crs.is_geographic(),
+            columns: None,
+        }
+    }
+
+    pub fn write_feature(&mut self, feature: &Feature) -> Result<()> {
+        if self.columns.is_none() {
+            let columns = feature
+                .properties
+                .iter()
+                .flatten()
+                .map(|(key, _)| key.clone())
+                .collect();
+            self.write_header(columns)?;
+        }
+        let columns = self.columns.as_ref().unwrap();
+        if let Some(key) = feature
+            .properties
+            .iter()
+            .flatten()
+            .map(|(key, _)| key)
+            .find(|key| !columns.contains(key))
+        {
+            bail!("A feature has a {key} property, but the first feature didn't");
+        }
+
+        let coords = linestring_coords(feature)?;
+        let mut record: Vec<String> = match self.geometry {
+            CsvGeometry::Coordinates => {
+                let (first, last) = (&coords[0], &coords[coords.len() - 1]);
+                vec![first[0], first[1], last[0], last[1]]
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect()
+            }
+            CsvGeometry::Wkt => vec![linestring_wkt(coords)],
+        };
+        for key in columns {
+            record.push(match feature.property(key) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(x)) => x.clone(),
+                Some(x) => x.to_string(),
+            });
+        }
+        self.writer.write_record(&record)?;
+        Ok(())
+    }
+
+    /// Flushes all output. This must be called after the last feature.
+    pub fn finish(mut self) -> Result<W> {
+        // Even without any features, write the geometry columns
+        if self.columns.is_none() {
+            self.write_header(Vec::new())?;
+        }
+        self.writer.flush()?;
+        self.writer
+            .into_inner()
+            .map_err(|err| anyhow::anyhow!("Couldn't flush CSV output: {}", err.error()))
+    }
+
+    fn write_header(&mut self, columns: Vec<String>) -> Result<()> {
+        let mut header: Vec<&str> = match self.geometry {
+            CsvGeometry::Coordinates if self.geographic => vec!["o_lon", "o_lat", "d_lon", "d_lat"],
+            CsvGeometry::Coordinates => vec!["o_x", "o_y", "d_x", "d_y"],
+            CsvGeometry::Wkt => vec!["geometry"],
+        };
+        header.extend(columns.iter().map(|x| x.as_str()));
+        self.writer.write_record(&header)?;
+        self.columns = Some(columns);
+        Ok(())
+    }
+}
+
+/// How many features `GeoParquetWriter` buffers before writing them.
+const PARQUET_BATCH_SIZE: usize = 10_000;
+
+/// The type of a GeoParquet property column.
+#[derive(Clone, Copy, PartialEq)]
+enum ColumnType {
+    Float64,
+    Boolean,
+    Utf8,
+}
+
+/// Writes features with LineString geometry to a GeoParquet file. The geometry is WKB-encoded, and
+/// each property becomes a typed column.
+///
+/// Parquet needs a schema upfront, so column types are inferred from the first batch of features:
+/// a property is numeric or boolean if all of its values in the batch are, and a string otherwise.
+/// Later features with a property that's not in the first batch, or a different type of value,
+/// cause an error, except that anything can be written to a string column. Missing properties are
+/// null. The metadata, if any, is recorded in the file's `odjitter` key.
+///
+/// The coordinates have to be in WGS84, the default CRS of GeoParquet. Other CRSs would need to be
+/// described in PROJJSON, which isn't supported.
+pub struct GeoParquetWriter<W: Write + Send> {
+    /// Only set until the first batch is written and the schema is known
+    inner: Option<W>,
+    writer: Option<ArrowWriter<W>>,
+    schema: Arc<Schema>,
+    columns: Vec<(String, ColumnType)>,
+    metadata: Option<String>,
+    buffer: Vec<Feature>,
+    /// [min x, min y, max x, max y] of every feature written
+    bbox: Option<[f64; 4]>,
+}
+
+impl<W: Write + Send> GeoParquetWriter<W> {
+    /// `crs` is what the coordinates of the features are in. It has to be WGS84.
+    pub fn new(writer: W, metadata: Option<&Metadata>, crs: &Crs) -> Result<Self> {
+        if *crs != Crs::wgs84() {
+            bail!(
+                "GeoParquet output has to be in WGS84, not {}; reproject the output first",
+                crs.name()
+            );
+        }
+        Ok(Self {
+            inner: Some(writer),
+            writer: None,
+            schema: Arc::new(Schema::empty()),
+            columns: Vec::new(),
+            metadata: metadata.map(|metadata| metadata.to_json().to_string()),
+            buffer: Vec::new(),
+            bbox: None,
+        })
+    }
+
+    pub fn write_feature(&mut self, feature: &Feature) -> Result<()> {
+        self.buffer.push(feature.clone());
+        if self.buffer.len() == PARQUET_BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Writes any remaining features and the file's footer. This must be called after the last
+    /// feature.
+    pub fn finish(mut self) -> Result<W> {
+        // Even without any features, the file needs a schema
+        if !self.buffer.is_empty() || self.writer.is_none() {
+            self.write_batch()?;
+        }
+        let mut writer = self.writer.unwrap();
+
+        let mut geo = json!({
+            "version": "1.0.0",
+            "primary_column": "geometry",
+            "columns": {
+                "geometry": {
+                    "encoding": "WKB",
+                    "geometry_types": ["LineString"],
+                },
+            },
+        });
+        if let Some(bbox) = self.bbox {
+            geo["columns"]["geometry"]["bbox"] = json!(bbox);
+        }
+        writer.append_key_value_metadata(KeyValue::new("geo".to_string(), geo.to_string()));
+        if let Some(metadata) = self.metadata {
+            writer.append_key_value_metadata(KeyValue::new("odjitter".to_string(), metadata));
+        }
+        let mut inner = writer.into_inner()?;
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    fn write_batch(&mut self) -> Result<()> {
+        let features = std::mem::take(&mut self.buffer);
+
+        if let Some(inner) = self.inner.take() {
+            self.columns = infer_column_types(&features);
+            let mut fields = vec![Field::new("geometry", DataType::Binary, false)];
+            for (key, column_type) in &self.columns {
+                let data_type = match column_type {
+                    ColumnType::Float64 => DataType::Float64,
+                    ColumnType::Boolean => DataType::Boolean,
+                    ColumnType::Utf8 => DataType::Utf8,
+                };
+                fields.push(Field::new(key, data_type, true));
+            }
+            self.schema = Arc::new(Schema::new(fields));
+            let props = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            self.writer = Some(ArrowWriter::try_new(
+                inner,
+                self.schema.clone(),
+                Some(props),
+            )?);
+        }
+
+        let mut geometry = BinaryBuilder::new();
+        for feature in &features {
+            if let Some(key) = feature
+                .properties
+                .iter()
+                .flatten()
+                .map(|(key, _)| key)
+                .find(|key| !self.columns.iter().any(|(k, _)| k == *key))
+            {
+                bail!("A feature has a {key} property, but no feature in the first batch did");
+            }
+            let coords = linestring_coords(feature)?;
+            for coord in coords {
+                let bbox = self
+                    .bbox
+                    .get_or_insert([coord[0], coord[1], coord[0], coord[1]]);
+                bbox[0] = bbox[0].min(coord[0]);
+                bbox[1] = bbox[1].min(coord[1]);
+                bbox[2] = bbox[2].max(coord[0]);
+                bbox[3] = bbox[3].max(coord[1]);
+            }
+            geometry.append_value(linestring_wkb(coords)?);
+        }
+        let mut arrays: Vec<ArrayRef> = vec![Arc::new(geometry.finish())];
+
+        for (key, column_type) in &self.columns {
+            let values = features
+                .iter()
+                .map(|f| f.property(key).filter(|x| !x.is_null()));
+            arrays.push(match column_type {
+                ColumnType::Float64 => {
+                    let mut builder = Float64Builder::new();
+                    for value in values {
+                        match value {
+                            Some(Value::Number(x)) => builder.append_value(x.as_f64().unwrap()),
+                            Some(x) => bail!("The {key} column was inferred to be numeric, but a later feature has {x}"),
+                            None => builder.append_null(),
+                        }
+                    }
+                    Arc::new(builder.finish())
+                }
+                ColumnType::Boolean => {
+                    let mut builder = BooleanBuilder::new();
+                    for value in values {
+                        match value {
+                            Some(Value::Bool(x)) => builder.append_value(*x),
+                            Some(x) => bail!("The {key} column was inferred to be boolean, but a later feature has {x}"),
+                            None => builder.append_null(),
+                        }
+                    }
+                    Arc::new(builder.finish())
+                }
+                ColumnType::Utf8 => {
+                    let mut builder = StringBuilder::new();
+                    for value in values {
+                        match value {
+                            Some(Value::String(x)) => builder.append_value(x),
+                            Some(x) => builder.append_value(x.to_string()),
+                            None => builder.append_null(),
+                        }
+                    }
+                    Arc::new(builder.finish())
+                }
+            });
+        }
+
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        self.writer.as_mut().unwrap().write(&batch)?;
+        Ok(())
+    }
+}
+
+/// Decides the type of every property, in the order they're first seen. A property that's always
+/// null is a string.
+fn infer_column_types(features: &[Feature]) -> Vec<(String, ColumnType)> {
+    let mut columns: Vec<(String, Option<ColumnType>)> = Vec::new();
+    for feature in features {
+        for (key, value) in feature.properties.iter().flatten() {
+            let column_type = match value {
+                Value::Null => None,
+                Value::Number(_) => Some(ColumnType::Float64),
+                Value::Bool(_) => Some(ColumnType::Boolean),
+                _ => Some(ColumnType::Utf8),
+            };
+            if let Some((_, existing)) = columns.iter_mut().find(|(k, _)| k == key) {
+                match (*existing, column_type) {
+                    (_, None) => {}
+                    (None, _) => *existing = column_type,
+                    (Some(x), Some(y)) if x != y => *existing = Some(ColumnType::Utf8),
+                    _ => {}
+                }
+            } else {
+                columns.push((key.clone(), column_type));
+            }
+        }
+    }
+    columns
+        .into_iter()
+        .map(|(key, column_type)| (key, column_type.unwrap_or(ColumnType::Utf8)))
+        .collect()
+}
+
+fn linestring_coords(feature: &Feature) -> Result<&Vec<Vec<f64>>> {
+    match feature.geometry.as_ref().map(|g| &g.value) {
+        Some(geojson::Value::LineString(coords)) => Ok(coords),
+        _ => bail!(
+            "Only LineStrings can be written, not {:?}",
+            feature.geometry
+        ),
+    }
+}
+
+fn linestring_wkb(coords: &[Vec<f64>]) -> Result<Vec<u8>> {
+    let line_string: LineString<f64> = coords
+        .iter()
+        .map(|coord| Coord {
+            x: coord[0],
+            y: coord[1],
+        })
+        .collect();
+    Ok(Geometry::LineString(line_string).to_wkb(CoordDimensions::xy())?)
+}
+
+fn linestring_wkt(coords: &[Vec<f64>]) -> String {
+    let points: Vec<String> = coords
+        .iter()
+        .map(|coord| format!("{} {}", coord[0], coord[1]))
+        .collect();
+    format!("LINESTRING({})", points.join(", "))
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+use geo_types::{Coord, LineString};
+use geojson::Feature;
+use serde_json::{Map, Value};
+
+use crate::input::{feature_columns, json_number, Keys};
+use crate::OdValue;
+
+/// Controls how `overline` aggregates LineStrings.
+pub struct OverlineOptions {
+    /// Which properties to sum. If this is empty, every property that `jitter` would treat as a
+    /// count is summed.
+    pub sum_keys: Vec<String>,
+    /// Which property names the origin zone. It's never summed, even if the zone names are numeric.
+    pub origin_key: String,
+    /// Which property names the destination zone. It's never summed, even if the zone names are
+    /// numeric.
+    pub destination_key: String,
+    /// Which property, if any, has a distance per trip. Like in `jitter`, it isn't a count, so it's
+    /// never summed.
+    pub distance_key: Option<String>,
+}
+
+/// Aggregates LineStrings, like the output of `jitter` or `route`, into segments that don't
+/// overlap, summing the counts of every input LineString along each segment. Segments only
+/// overlap when they share exactly the same vertices, like routes along the same road network.
+/// Consecutive segments with the same sums are joined together, unless other segments branch off
+/// in between. A property missing from some LineStrings counts as 0 for them, and other properties
+/// are dropped.
+pub fn overline<I: IntoIterator<Item = Result<Feature>>>(
+    features: I,
+    options: &OverlineOptions,
+) -> Result<Vec<Feature>> {
+    let keys = Keys {
+        origin: options.origin_key.clone(),
+        destination: options.destination_key.clone(),
+        distance: options.distance_key.clone(),
+    };
+    // Properties to sum, in the order they're first seen
+    let mut columns: Vec<String> = options.sum_keys.clone();
+    let mut segments: Vec<(Coord<f64>, Coord<f64>)> = Vec::new();
+    let mut sums: Vec<Vec<f64>> = Vec::new();
+    let mut segment_ids: HashMap<(NodeKey, NodeKey), usize> = HashMap::new();
+
+    for (idx, feature) in features.into_iter().enumerate() {
+        let feature = feature?;
+        let name = format!("Feature {idx}");
+        let line_string: LineString<f64> = match feature.geometry {
+            Some(ref geometry) => match geometry.value.clone().try_into() {
+                Ok(line_string) => line_string,
+                Err(_) => bail!("{name} isn't a LineString"),
+            },
+            None => bail!("{name} doesn't have a geometry"),
+        };
+
+        let mut counts = Vec::new();
+        for (key, value) in feature_columns(&feature, &name, &keys)? {
+            let is_summed = options.sum_keys.is_empty() || options.sum_keys.contains(&key);
+            match value {
+                OdValue::Count(count) if is_summed => {
+                    let column = match columns.iter().position(|x| *x == key) {
+                        Some(column) => column,
+                        None => {
+                            columns.push(key);
+                            columns.len() - 1
+                        }
+                    };
+                    counts.push((column, count));
+                }
+                // Treat empty values like missing ones
+                OdValue::Attribute(Value::Null) => {}
+                OdValue::Attribute(Value::String(ref value)) if value.is_empty() => {}
+                OdValue::Attribute(value) if !options.sum_keys.is_empty() && is_summed => {
+                    bail!("{name} has a non-numeric {key} value {value}");
+                }
+                _ => {}
+            }
+        }
+
+        for line in line_string.lines() {
+            let (start, end) = (node_key(line.start), node_key(line.end));
+            if start == end {
+                continue;
+            }
+            // The direction of travel doesn't matter
+            let key = if start < end {
+                (start, end)
+            } else {
+                (end, start)
+            };
+            let id = *segment_ids.entry(key).or_insert_with(|| {
+                segments.push((line.start, line.end));
+                sums.push(Vec::new());
+                segments.len() - 1
+            });
+            let segment_sums = &mut sums[id];
+            for (column, count) in &counts {
+                if segment_sums.len() <= *column {
+                    segment_sums.resize(*column + 1, 0.0);
+                }
+                segment_sums[*column] += count;
+            }
+        }
+    }
+    for segment_sums in &mut sums {
+        segment_sums.resize(columns.len(), 0.0);
+    }
+
+    // Join segments into longer LineStrings where nothing else branches off
+    let mut segments_per_node: HashMap<NodeKey, Vec<usize>> = HashMap::new();
+    for (id, (start, end)) in segments.iter().enumerate() {
+        segments_per_node
+            .entry(node_key(*start))
+            .or_default()
+            .push(id);
+        segments_per_node
+            .entry(node_key(*end))
+            .or_default()
+            .push(id);
+    }
+    let next_segment = |node: Coord<f64>, current: usize, visited: &[bool]| {
+        let ids = &segments_per_node[&node_key(node)];
+        if ids.len() != 2 {
+            return None;
+        }
+        let next = if ids[0] == current { ids[1] } else { ids[0] };
+        if visited[next] || sums[next] != sums[current] {
+            return None;
+        }
+        let (start, end) = segments[next];
+        if node_key(start) == node_key(node) {
+            Some((next, end))
+        } else {
+            Some((next, start))
+        }
+    };
+
+    let mut visited = vec![false; segments.len()];
+    let mut output = Vec::new();
+    for id in 0..segments.len() {
+        if visited[id] {
+            continue;
+        }
+        visited[id] = true;
+        let (start, end) = segments[id];
+        let mut coords = VecDeque::from([start, end]);
+        let mut current = id;
+        while let Some((next, pt)) = next_segment(*coords.back().unwrap(), current, &visited) {
+            visited[next] = true;
+            coords.push_back(pt);
+            current = next;
+        }
+        current = id;
+        while let Some((next, pt)) = next_segment(*coords.front().unwrap(), current, &visited) {
+            visited[next] = true;
+            coords.push_front(pt);
+            current = next;
+        }
+
+        let mut properties = Map::new();
+        for (key, sum) in columns.iter().zip(&sums[id]) {
+            properties.insert(key.clone(), json_number(*sum));
+        }
+        output.push(Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::from(&LineString::new(coords.into()))),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        });
+    }
+    Ok(output)
+}
+
+/// Identifies a vertex by its exact coordinates.
+type NodeKey = (u64, u64);
+
+fn node_key(pt: Coord<f64>) -> NodeKey {
+    (pt.x.to_bits(), pt.y.to_bits())
+}
@@ -0,0 +1,153 @@
+use std::io::BufReader;
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use fs_err::File;
+use geo_types::{Coord, Rect};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+/// A single-band grid of values, like population counts, with square-ish cells aligned to the x
+/// and y axes.
+#[derive(Clone)]
+pub struct Raster {
+    /// The coordinates of the top-left corner of the top-left cell
+    pub top_left: Coord<f64>,
+    /// The size of each cell along the x axis
+    pub cell_width: f64,
+    /// The size of each cell along the y axis. Rows go from north to south, so y decreases with
+    /// each row.
+    pub cell_height: f64,
+    pub columns: usize,
+    pub rows: usize,
+    /// One value per cell in row-major order, starting from the top-left. Cells without data are
+    /// NaN.
+    pub values: Vec<f64>,
+}
+
+impl Raster {
+    pub fn get(&self, row: usize, column: usize) -> f64 {
+        self.values[row * self.columns + column]
+    }
+
+    pub fn cell_bounds(&self, row: usize, column: usize) -> Rect<f64> {
+        let x1 = self.top_left.x + (column as f64) * self.cell_width;
+        let y1 = self.top_left.y - (row as f64) * self.cell_height;
+        Rect::new((x1, y1), (x1 + self.cell_width, y1 - self.cell_height))
+    }
+
+    /// Returns the ranges of rows and columns with cells that might overlap the bounding box.
+    pub(crate) fn cells_overlapping(&self, bounds: Rect<f64>) -> (Range<usize>, Range<usize>) {
+        let clamp = |value: f64, max: usize| (value.max(0.0) as usize).min(max);
+        let first_row = (self.top_left.y - bounds.max().y) / self.cell_height;
+        let last_row = (self.top_left.y - bounds.min().y) / self.cell_height;
+        let first_column = (bounds.min().x - self.top_left.x) / self.cell_width;
+        let last_column = (bounds.max().x - self.top_left.x) / self.cell_width;
+        let rows = clamp(first_row.floor(), self.rows)..clamp(last_row.ceil(), self.rows);
+        let columns =
+            clamp(first_column.floor(), self.columns)..clamp(last_column.ceil(), self.columns);
+        (rows, columns)
+    }
+}
+
+/// Read a single-band GeoTIFF file. Only rasters georeferenced with the `ModelPixelScale` and
+/// `ModelTiepoint` tags (so north-up, without rotation) are supported. The coordinate system isn't
+/// checked here; it should match the zones, and `read_crs` finds it. Cells matching the GDAL nodata
+/// value, if there is one, are treated as missing.
+pub fn load_geotiff(path: &str) -> Result<Raster> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    if !matches!(decoder.colortype()?, ColorType::Gray(_)) {
+        bail!("{path} isn't a single-band raster");
+    }
+    let (columns, rows) = decoder.dimensions()?;
+    let (columns, rows) = (columns as usize, rows as usize);
+
+    let scale = decoder
+        .find_tag(Tag::ModelPixelScaleTag)?
+        .map(|value| value.into_f64_vec())
+        .transpose()?;
+    let tiepoint = decoder
+        .find_tag(Tag::ModelTiepointTag)?
+        .map(|value| value.into_f64_vec())
+        .transpose()?;
+    let (scale, tiepoint) = match (scale, tiepoint) {
+        (Some(scale), Some(tiepoint)) if scale.len() >= 2 && tiepoint.len() >= 6 => {
+            (scale, tiepoint)
+        }
+        _ => bail!("{path} isn't georeferenced with ModelPixelScale and ModelTiepoint tags"),
+    };
+    // The tiepoint maps raster position (I, J) to model coordinates (X, Y)
+    let top_left = Coord {
+        x: tiepoint[3] - tiepoint[0] * scale[0],
+        y: tiepoint[4] + tiepoint[1] * scale[1],
+    };
+
+    let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+        Some(value) => value
+            .into_string()?
+            .trim_matches('\0')
+            .trim()
+            .parse::<f64>()
+            .ok(),
+        None => None,
+    };
+
+    let mut values: Vec<f64> = match decoder.read_image()? {
+        DecodingResult::U8(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::U16(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::U32(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::U64(x) => x.into_iter().map(|x| x as f64).collect(),
+        DecodingResult::F32(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::F64(x) => x,
+        DecodingResult::I8(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::I16(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::I32(x) => x.into_iter().map(f64::from).collect(),
+        DecodingResult::I64(x) => x.into_iter().map(|x| x as f64).collect(),
+    };
+    if values.len() != rows * columns {
+        bail!(
+            "{path} has {} values, but expected {rows} rows and {columns} columns",
+            values.len()
+        );
+    }
+    if let Some(nodata) = nodata {
+        for value in &mut values {
+            if *value == nodata {
+                *value = f64::NAN;
+            }
+        }
+    }
+
+    Ok(Raster {
+        top_left,
+        cell_width: scale[0],
+        cell_height: scale[1],
+        columns,
+        rows,
+        values,
+    })
+}
+
+/// Reads the EPSG code of a GeoTIFF's projected or geographic coordinate system from its GeoKeys,
+/// if it has one.
+pub(crate) fn read_geotiff_epsg(path: &str) -> Result<Option<u32>> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let keys = match decoder.find_tag(Tag::GeoKeyDirectoryTag)? {
+        Some(value) => value.into_u16_vec()?,
+        None => return Ok(None),
+    };
+    // After a header of 4 values, each key has an ID, a tag location, a count and a value. The
+    // value is inline when the location is 0.
+    let code = |id: u16| {
+        keys.get(4..)?
+            .chunks_exact(4)
+            .find(|key| key[0] == id && key[1] == 0)
+            .map(|key| key[3])
+    };
+    // ProjectedCSTypeGeoKey, then GeographicTypeGeoKey. 32767 means user-defined.
+    match code(3072).or_else(|| code(2048)) {
+        Some(code) if code > 0 && code != 32767 => Ok(Some(code.into())),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,183 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use anyhow::{bail, Result};
+use geo_types::{Coord, LineString, Point};
+use geojson::Feature;
+use ordered_float::NotNan;
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
+
+use crate::{scrape_lines, Crs, WeightedLineString};
+
+/// A graph of roads, used to replace straight desire lines with the shortest path along the
+/// network.
+pub struct RoadNetwork {
+    nodes: Vec<Coord<f64>>,
+    /// For every node, the neighbors and the cost to reach them. Costs are always finite.
+    edges: Vec<Vec<(usize, f64)>>,
+    closest_node: RTree<GeomWithData<[f64; 2], usize>>,
+
+    // Reused by every query, so routing doesn't allocate per node. Between queries, every cost is
+    // infinite and the queue is empty.
+    cost: Vec<f64>,
+    previous: Vec<usize>,
+    /// The nodes whose cost was set by the current query
+    visited: Vec<usize>,
+    queue: BinaryHeap<Reverse<(NotNan<f64>, usize)>>,
+}
+
+impl RoadNetwork {
+    /// Builds the network from LineStrings in a file in any format `read_features` supports. See
+    /// `from_lines` for how `weight_key` is used.
+    pub fn load(path: &str, weight_key: Option<String>, crs: &Crs) -> Result<RoadNetwork> {
+        RoadNetwork::from_lines(scrape_lines(path, weight_key)?, crs)
+    }
+
+    /// Builds the network from LineStrings. Every vertex becomes a node, and LineStrings are only
+    /// connected where they share a vertex with exactly the same coordinates. Roads can be used in
+    /// both directions. The cost of each segment is its length in meters, multiplied by the
+    /// LineString's weight, so a weight of 2 makes a road twice as costly as its length. Roads with
+    /// an infinite weight can't be used at all.
+    pub fn from_lines(lines: Vec<WeightedLineString>, crs: &Crs) -> Result<RoadNetwork> {
+        let metric = crs.metric();
+        let mut nodes = Vec::new();
+        let mut edges: Vec<Vec<(usize, f64)>> = Vec::new();
+        let mut node_ids: HashMap<(u64, u64), usize> = HashMap::new();
+        let mut node_id = |pt: Coord<f64>| {
+            *node_ids
+                .entry((pt.x.to_bits(), pt.y.to_bits()))
+                .or_insert_with(|| {
+                    nodes.push(pt);
+                    edges.push(Vec::new());
+                    nodes.len() - 1
+                })
+        };
+
+        let mut segments = Vec::new();
+        for line in lines {
+            if line.weight.is_nan() || line.weight < 0.0 {
+                bail!("Road weights can't be negative, but one is {}", line.weight);
+            }
+            for segment in line.line_string.lines() {
+                let (from, to) = (node_id(segment.start), node_id(segment.end));
+                let cost = metric.length(segment) * line.weight;
+                // Infinite weights, or zero lengths multiplied by them, make the road unusable
+                if from != to && cost.is_finite() {
+                    segments.push((from, to, cost));
+                }
+            }
+        }
+        for (from, to, cost) in segments {
+            edges[from].push((to, cost));
+            edges[to].push((from, cost));
+        }
+
+        let closest_node = RTree::bulk_load(
+            nodes
+                .iter()
+                .enumerate()
+                .map(|(id, pt)| GeomWithData::new([pt.x, pt.y], id))
+                .collect(),
+        );
+        Ok(RoadNetwork {
+            cost: vec![f64::INFINITY; nodes.len()],
+            previous: vec![usize::MAX; nodes.len()],
+            visited: Vec::new(),
+            queue: BinaryHeap::new(),
+            nodes,
+            edges,
+            closest_node,
+        })
+    }
+
+    /// Finds the lowest cost path between the nodes closest to `from` and `to`, using Dijkstra's
+    /// algorithm. The result starts at `from` and ends at `to`. Returns `None` if the nodes aren't
+    /// connected.
+    pub fn route(&mut self, from: Point<f64>, to: Point<f64>) -> Option<LineString<f64>> {
+        let start = self
+            .closest_node
+            .nearest_neighbor(&[from.x(), from.y()])?
+            .data;
+        let end = self.closest_node.nearest_neighbor(&[to.x(), to.y()])?.data;
+
+        let path = self.shortest_path(start, end);
+        // Only reset the nodes this query reached
+        for node in self.visited.drain(..) {
+            self.cost[node] = f64::INFINITY;
+        }
+        self.queue.clear();
+
+        let mut coords = vec![from.0];
+        coords.extend(path?.into_iter().map(|node| self.nodes[node]));
+        coords.push(to.0);
+        coords.dedup();
+        if coords.len() == 1 {
+            // The line needs at least two points
+            coords.push(to.0);
+        }
+        Some(LineString::new(coords))
+    }
+
+    /// Returns the nodes from `start` to `end` along the lowest cost path. The caller has to reset
+    /// the buffers afterwards.
+    fn shortest_path(&mut self, start: usize, end: usize) -> Option<Vec<usize>> {
+        self.cost[start] = 0.0;
+        self.visited.push(start);
+        // Edge costs are finite and never negative, so the total cost is never NaN
+        self.queue.push(Reverse((NotNan::default(), start)));
+        while let Some(Reverse((node_cost, node))) = self.queue.pop() {
+            if node == end {
+                break;
+            }
+            // Skip stale entries
+            if node_cost.into_inner() > self.cost[node] {
+                continue;
+            }
+            for (next, edge_cost) in &self.edges[node] {
+                let next_cost = node_cost + edge_cost;
+                if next_cost.into_inner() < self.cost[*next] {
+                    if self.cost[*next] == f64::INFINITY {
+                        self.visited.push(*next);
+                    }
+                    self.cost[*next] = next_cost.into_inner();
+                    self.previous[*next] = node;
+                    self.queue.push(Reverse((next_cost, *next)));
+                }
+            }
+        }
+        if !self.cost[end].is_finite() {
+            return None;
+        }
+
+        let mut path = vec![end];
+        while *path.last().unwrap() != start {
+            path.push(self.previous[*path.last().unwrap()]);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Replaces the geometry of a feature with a LineString, like the output of `jitter`, with the
+    /// route between its first and last points. The properties are unchanged. If there's no route,
+    /// the feature is unchanged and this returns false.
+    pub fn route_feature(&mut self, feature: &mut Feature) -> Result<bool> {
+        let line_string: LineString<f64> = match feature.geometry {
+            Some(ref geometry) => match geometry.value.clone().try_into() {
+                Ok(line_string) => line_string,
+                Err(_) => bail!("Only LineStrings can be routed"),
+            },
+            None => bail!("Only LineStrings can be routed"),
+        };
+        let (from, to) = match (line_string.0.first(), line_string.0.last()) {
+            (Some(from), Some(to)) => (Point::from(*from), Point::from(*to)),
+            _ => bail!("Can't route an empty LineString"),
+        };
+        if let Some(route) = self.route(from, to) {
+            feature.geometry = Some(geojson::Geometry::from(&route));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
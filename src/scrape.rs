@@ -1,32 +1,20 @@
-use std::io::BufReader;
-
 use anyhow::{bail, Result};
-use fs_err::File;
 use geo::CoordsIter;
 use geo_types::Geometry;
-use geojson::FeatureReader;
+use geojson::Feature;
 
-use crate::WeightedPoint;
+use crate::{read_features, WeightedLineString, WeightedPoint, WeightedPolygon};
 
-/// Extract all points from a GeoJSON file. If `weight_key` is specified, use this numeric property
-/// per feature as a relative weight for the point. If unspecified, every point will be equally
-/// weighted.
+/// Extract all points from a file in any format `read_features` supports. If `weight_key` is
+/// specified, use this numeric property per feature as a relative weight for the point. If
+/// unspecified, every point will be equally weighted.
 ///
 /// TODO: Note that the returned points are not deduplicated.
 pub fn scrape_points(path: &str, weight_key: Option<String>) -> Result<Vec<WeightedPoint>> {
-    let reader = FeatureReader::from_reader(BufReader::new(File::open(path)?));
     let mut points = Vec::new();
-    for feature in reader.features() {
+    for feature in read_features(path)? {
         let feature = feature?;
-        let weight = if let Some(ref key) = weight_key {
-            if let Some(weight) = feature.property(key).and_then(|x| x.as_f64()) {
-                weight
-            } else {
-                bail!("Feature doesn't have a numeric {} key: {:?}", key, feature);
-            }
-        } else {
-            1.0
-        };
+        let weight = get_weight(&feature, &weight_key)?;
         if let Some(geom) = feature.geometry {
             let geom: Geometry<f64> = geom.try_into()?;
             for pt in geom.coords_iter() {
@@ -39,3 +27,69 @@ pub fn scrape_points(path: &str, weight_key: Option<String>) -> Result<Vec<Weigh
     }
     Ok(points)
 }
+
+/// Extract all LineStrings from a file in any format `read_features` supports, splitting
+/// MultiLineStrings apart. Features with any other geometry type are skipped. If `weight_key` is
+/// specified, use this numeric property per feature as a relative weight for the line. If
+/// unspecified, every line will be equally weighted per unit of length.
+pub fn scrape_lines(path: &str, weight_key: Option<String>) -> Result<Vec<WeightedLineString>> {
+    let mut lines = Vec::new();
+    for feature in read_features(path)? {
+        let feature = feature?;
+        let weight = get_weight(&feature, &weight_key)?;
+        if let Some(geom) = feature.geometry {
+            let geom: Geometry<f64> = geom.try_into()?;
+            let line_strings = match geom {
+                Geometry::LineString(ls) => vec![ls],
+                Geometry::MultiLineString(mls) => mls.0,
+                _ => Vec::new(),
+            };
+            for line_string in line_strings {
+                // Skip degenerate lines without any length
+                if line_string.0.len() >= 2 {
+                    lines.push(WeightedLineString {
+                        line_string,
+                        weight,
+                    });
+                }
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Extract all polygons from a file in any format `read_features` supports, splitting
+/// MultiPolygons apart. Features with any other geometry type are skipped. If `weight_key` is
+/// specified, use this numeric property per feature as a relative weight for the polygon. If
+/// unspecified, every polygon will be equally weighted per unit of area.
+pub fn scrape_polygons(path: &str, weight_key: Option<String>) -> Result<Vec<WeightedPolygon>> {
+    let mut polygons = Vec::new();
+    for feature in read_features(path)? {
+        let feature = feature?;
+        let weight = get_weight(&feature, &weight_key)?;
+        if let Some(geom) = feature.geometry {
+            let geom: Geometry<f64> = geom.try_into()?;
+            let list = match geom {
+                Geometry::Polygon(p) => vec![p],
+                Geometry::MultiPolygon(mp) => mp.0,
+                _ => Vec::new(),
+            };
+            for polygon in list {
+                polygons.push(WeightedPolygon { polygon, weight });
+            }
+        }
+    }
+    Ok(polygons)
+}
+
+fn get_weight(feature: &Feature, weight_key: &Option<String>) -> Result<f64> {
+    if let Some(ref key) = weight_key {
+        if let Some(weight) = feature.property(key).and_then(|x| x.as_f64()) {
+            Ok(weight)
+        } else {
+            bail!("Feature doesn't have a numeric {} key: {:?}", key, feature);
+        }
+    } else {
+        Ok(1.0)
+    }
+}
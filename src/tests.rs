@@ -1,13 +1,24 @@
 use std::collections::{HashMap, HashSet};
 
-use geo_types::Point;
+use geo::algorithm::contains::Contains;
+use geo::algorithm::euclidean_distance::EuclideanDistance;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use geo::algorithm::interior_point::InteriorPoint;
+use geo_types::{LineString, MultiPolygon, Point, Polygon, Rect};
 use geojson::Feature;
 use ordered_float::NotNan;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use serde_json::{Map, Value};
 
-use crate::{disaggregate, jitter, load_zones, scrape_points, Options, Subsample};
+use crate::{
+    disaggregate, disaggregate_trips, jitter, jitter_trips, load_geotiff, load_points, load_zones,
+    overline, read_crs, scrape_lines, scrape_points, Crs, CsvGeometry, CsvWriter, FlatGeobufWriter,
+    GeoJsonSeqWriter, GeoJsonWriter, GeoParquetWriter, Integerisation, Metadata, OdInput, OdRecord,
+    OdValue, Options, OverlineOptions, Raster, RetryFallback, RoadNetwork, Subsample, UnknownZones,
+    WeightedLineString, WeightedPoint, WeightedPolygon, Zones,
+};
 
 #[test]
 fn test_sums_match() {
@@ -19,10 +30,7 @@ fn test_sums_match() {
         let options = Options {
             subsample_origin: Subsample::WeightedPoints(subpoints.clone()),
             subsample_destination: Subsample::WeightedPoints(subpoints),
-            origin_key: "geo_code1".to_string(),
-            destination_key: "geo_code2".to_string(),
-            min_distance_meters: 1.0,
-            deduplicate_pairs: false,
+            ..Default::default()
         };
         let mut rng = StdRng::seed_from_u64(42);
         let mut output = Vec::new();
@@ -69,12 +77,10 @@ fn test_different_subpoints() {
         .collect();
 
     let options = Options {
-        subsample_origin: Subsample::RandomPoints,
         subsample_destination: Subsample::WeightedPoints(destination_subpoints),
         origin_key: "origin".to_string(),
         destination_key: "destination".to_string(),
-        min_distance_meters: 1.0,
-        deduplicate_pairs: false,
+        ..Default::default()
     };
     let disaggregation_threshold = 1;
     let disaggregation_key = "walk".to_string();
@@ -130,45 +136,1441 @@ fn test_different_subpoints() {
 #[test]
 fn test_disaggregate() {
     let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = Options::default();
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut output = Vec::new();
+    disaggregate("data/od.csv", &zones, &mut rng, options, |feature| {
+        output.push(feature);
+        Ok(())
+    })
+    .unwrap();
+
+    // Note "all" has no special meaning to the disaggregate call. The user should probably remove
+    // it from the input or ignore it in the output.
+    let input_sums = sum_trips_input("data/od.csv", &["all", "car_driver", "foot"]);
+    let mut sums_per_mode: HashMap<String, usize> = HashMap::new();
+    for feature in output {
+        let mode = feature
+            .property("mode")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        *sums_per_mode.entry(mode).or_insert(0) += 1;
+    }
+    for (mode, input_sum) in input_sums {
+        let output_sum = sums_per_mode[&mode];
+        assert!(
+            input_sum as usize == output_sum,
+            "Number of {} trips in input {} and disaggregated output {} don't match",
+            mode,
+            input_sum,
+            output_sum,
+        );
+    }
+}
+
+#[test]
+fn test_disaggregate_deterministic() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let options = Options::default();
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut output = Vec::new();
+        disaggregate("data/od.csv", &zones, &mut rng, options, |feature| {
+            output.push(feature);
+            Ok(())
+        })
+        .unwrap();
+        outputs.push(output);
+    }
+    assert!(
+        outputs[0] == outputs[1],
+        "Running disaggregate twice with the same seed produced different output"
+    );
+
+    // Modes within a row should follow the order of the CSV header, where all is the first
+    // numeric column
+    assert_eq!(
+        outputs[0][0]
+            .property("mode")
+            .and_then(|mode| mode.as_str()),
+        Some("all")
+    );
+}
+
+#[test]
+fn test_integerisation() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    // The input has 2.7 walk, 4.9 bike, and 12 car trips
+    for (integerisation, walk, bike, car) in [
+        (Integerisation::Truncate, 0, 3, 10),
+        (Integerisation::Round, 2, 6, 12),
+        (Integerisation::TruncateReplicateSample, 3, 5, 12),
+    ] {
+        let options = Options {
+            integerisation,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut sums_per_mode: HashMap<String, usize> = HashMap::new();
+        disaggregate(
+            "data/od_fractional.csv",
+            &zones,
+            &mut rng,
+            options,
+            |feature| {
+                let mode = feature.property("mode").unwrap().as_str().unwrap();
+                *sums_per_mode.entry(mode.to_string()).or_insert(0) += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        for (mode, expected) in [("walk", walk), ("bike", bike), ("car", car)] {
+            let actual = sums_per_mode.get(mode).cloned().unwrap_or(0);
+            assert_eq!(
+                actual, expected,
+                "With {:?}, got {} {} trips, but expected {}",
+                integerisation, actual, mode, expected
+            );
+        }
+    }
+
+    // Stochastic rounding picks the count rounded up or down, so the total for each mode is
+    // between the truncated and rounded up totals, and matches the input on average
+    let runs = 200;
+    let mut totals: HashMap<String, usize> = HashMap::new();
+    for seed in 0..runs {
+        let options = Options {
+            integerisation: Integerisation::Stochastic,
+            ..Default::default()
+        };
+        let mut sums_per_mode: HashMap<String, usize> = HashMap::new();
+        disaggregate(
+            "data/od_fractional.csv",
+            &zones,
+            &mut StdRng::seed_from_u64(seed),
+            options,
+            |feature| {
+                let mode = feature.property("mode").unwrap().as_str().unwrap();
+                *sums_per_mode.entry(mode.to_string()).or_insert(0) += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        for (mode, min, max) in [("walk", 0, 5), ("bike", 3, 7), ("car", 10, 15)] {
+            let actual = sums_per_mode.get(mode).cloned().unwrap_or(0);
+            assert!(
+                (min..=max).contains(&actual),
+                "With Stochastic and seed {seed}, got {actual} {mode} trips"
+            );
+            *totals.entry(mode.to_string()).or_insert(0) += actual;
+        }
+    }
+    for (mode, expected) in [("walk", 2.7), ("bike", 4.9), ("car", 12.0)] {
+        let mean = totals[mode] as f64 / runs as f64;
+        assert!(
+            (mean - expected).abs() < 0.3,
+            "With Stochastic, the mean number of {mode} trips is {mean}, but expected {expected}"
+        );
+    }
+}
+
+#[test]
+fn test_stable_rng() {
+    // ChaCha8Rng promises the same output for the same seed across releases, so archived runs can
+    // be reproduced exactly. If this test breaks, the output of seeded runs has changed.
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = Options::default();
+    let mut rng = ChaCha8Rng::seed_from_u64(42);
+    let mut output = Vec::new();
+    disaggregate("data/od.csv", &zones, &mut rng, options, |feature| {
+        output.push(feature);
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(
+        output[0].geometry.as_ref().unwrap().value,
+        geojson::Value::LineString(vec![
+            vec![-3.2163669131877732, 55.93699448306761],
+            vec![-3.204552353130082, 55.93314660584865],
+        ])
+    );
+}
+
+#[test]
+fn test_rng_with_small_seed() {
+    // Per-row RNGs are derived from the base seed, which mustn't depend on the size of the seed
+    struct TinyRng(u32);
+    impl rand::RngCore for TinyRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+            self.0
+        }
+        fn next_u64(&mut self) -> u64 {
+            ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(4) {
+                let bytes = self.next_u32().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+    impl SeedableRng for TinyRng {
+        type Seed = [u8; 4];
+        fn from_seed(seed: Self::Seed) -> Self {
+            TinyRng(u32::from_le_bytes(seed))
+        }
+    }
+
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let trips = jitter_trips(
+        "data/od.csv",
+        &zones,
+        10,
+        "all".to_string(),
+        &mut TinyRng(42),
+        Options::default(),
+    )
+    .unwrap()
+    .collect::<anyhow::Result<Vec<_>>>()
+    .unwrap();
+    assert!(!trips.is_empty());
+}
+
+#[test]
+fn test_unknown_zones() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = |unknown_zones| Options {
+        unknown_zones,
+        ..Default::default()
+    };
+
+    let err = disaggregate(
+        "data/od_unknown_zones.csv",
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(UnknownZones::Fail),
+        |_| Ok(()),
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Unknown destination zone X99999999");
+
+    let rejects_path = std::env::temp_dir().join("odjitter_test_unknown_zones.csv");
+    let mut output = Vec::new();
+    let summary = disaggregate(
+        "data/od_unknown_zones.csv",
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(UnknownZones::Reject(rejects_path.clone())),
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+    // Only the rows with known zones are used
+    assert_eq!(output.len(), 5);
+    assert_eq!(summary.rows_skipped, 2);
+    assert_eq!(summary.trips_skipped, 11.5);
+    assert_eq!(
+        summary.unknown_zones.into_iter().collect::<Vec<_>>(),
+        vec![
+            ("X99999999".to_string(), 11.5),
+            ("Y88888888".to_string(), 4.5)
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&rejects_path).unwrap(),
+        "geo_code1,geo_code2,walk,bike\nS02001616,X99999999,2,5\nY88888888,X99999999,4,0.5\n"
+    );
+
+    // Rejected rows are written exactly as they were read
+    let csv = "walk,geo_code1,geo_code2,bike\n007,S02001616,X99999999,1.50\n";
+    disaggregate(
+        OdInput::csv_reader(csv.as_bytes()),
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(UnknownZones::Reject(rejects_path.clone())),
+        |_| Ok(()),
+    )
+    .unwrap();
+    assert_eq!(std::fs::read_to_string(&rejects_path).unwrap(), csv);
+}
+
+#[test]
+fn test_retry_fallback() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    // With only one subpoint per zone, trips within a zone can never be 1 meter long
+    let subpoints: Vec<WeightedPoint> = zones
+        .values()
+        .map(|zone| WeightedPoint {
+            point: zone.interior_point().unwrap(),
+            weight: 1.0,
+        })
+        .collect();
+    // Put the intrazonal rows last, after rows that can be jittered
+    let mut reader = csv::Reader::from_path("data/od.csv").unwrap();
+    let headers = reader.headers().unwrap().clone();
+    let (intrazonal, interzonal): (Vec<_>, Vec<_>) = reader
+        .records()
+        .map(|rec| rec.unwrap())
+        .partition(|rec| rec[0] == rec[1]);
+    let num_rows = intrazonal.len() + interzonal.len();
+    let num_intrazonal = 7;
+    assert_eq!(intrazonal.len(), num_intrazonal);
+    let path = std::env::temp_dir().join("odjitter_test_retry_fallback.csv");
+    let mut writer = csv::Writer::from_path(&path).unwrap();
+    writer.write_record(&headers).unwrap();
+    for rec in interzonal.iter().chain(&intrazonal) {
+        writer.write_record(rec).unwrap();
+    }
+    writer.flush().unwrap();
+
+    for retry_fallback in [
+        RetryFallback::Error,
+        RetryFallback::AcceptClosest,
+        RetryFallback::Drop,
+    ] {
+        let options = Options {
+            subsample_origin: Subsample::WeightedPoints(subpoints.clone()),
+            subsample_destination: Subsample::WeightedPoints(subpoints.clone()),
+            max_attempts: 10,
+            retry_fallback,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut output = Vec::new();
+        let result = jitter(
+            path.as_path(),
+            &zones,
+            100_000,
+            "all".to_string(),
+            &mut rng,
+            options,
+            |feature| {
+                output.push(feature);
+                Ok(())
+            },
+        );
+        match retry_fallback {
+            RetryFallback::Error => {
+                // The file is checked before any trips are produced
+                let err = result.unwrap_err().to_string();
+                assert!(err.starts_with("No points from"), "Unexpected error {err}");
+                assert!(output.is_empty());
+            }
+            RetryFallback::AcceptClosest => {
+                let summary = result.unwrap();
+                assert_eq!(output.len(), num_rows);
+                assert_eq!(summary.trips_violating_constraints, num_intrazonal);
+                assert_eq!(summary.trips_dropped, 0);
+            }
+            RetryFallback::Drop => {
+                let summary = result.unwrap();
+                assert_eq!(output.len(), num_rows - num_intrazonal);
+                assert_eq!(summary.trips_violating_constraints, 0);
+                assert_eq!(summary.trips_dropped, num_intrazonal);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_feasibility_checked_upfront() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    // Enough rows for a few batches, and only the last row is impossible: walking trips at most 1
+    // meter long between different zones
+    let num_rows = 25_000;
+    let records = move || {
+        (0..num_rows).map(move |idx| {
+            let (destination, mode) = if idx == num_rows - 1 {
+                ("S02001620", "foot")
+            } else {
+                ("S02001616", "car_driver")
+            };
+            OdRecord {
+                origin: "S02001616".to_string(),
+                destination: destination.to_string(),
+                columns: vec![(mode.to_string(), OdValue::Count(1.0))],
+            }
+        })
+    };
+    let path = std::env::temp_dir().join("odjitter_test_feasibility.csv");
+    let mut writer = csv::Writer::from_path(&path).unwrap();
+    writer
+        .write_record(["geo_code1", "geo_code2", "car_driver", "foot"])
+        .unwrap();
+    for record in records() {
+        let count = |mode| {
+            if record.columns[0].0 == mode {
+                "1"
+            } else {
+                "0"
+            }
+        };
+        writer
+            .write_record([
+                record.origin.as_str(),
+                record.destination.as_str(),
+                count("car_driver"),
+                count("foot"),
+            ])
+            .unwrap();
+    }
+    writer.flush().unwrap();
+    let options = || Options {
+        max_distance_meters_per_mode: HashMap::from([("foot".to_string(), 1.0)]),
+        retry_fallback: RetryFallback::Error,
+        ..Default::default()
+    };
+    let expected_err = "No points from S02001616 to S02001620 can be between 1 and 1 meters apart";
+
+    // A file is checked before any trips are produced
+    let mut output = Vec::new();
+    let err = disaggregate(
+        path.as_path(),
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(),
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), expected_err);
+    assert!(output.is_empty());
+
+    // In-memory records can only be read once, so the impossible row is only detected when it's
+    // reached
+    let mut trips = disaggregate_trips(
+        OdInput::records(records()),
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(),
+    )
+    .unwrap();
+    assert!(trips.next().unwrap().is_ok());
+    let err = trips.find_map(|trip| trip.err()).unwrap().to_string();
+    assert_eq!(err, expected_err);
+}
+
+#[test]
+fn test_max_distance() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = |max_distance_meters, retry_fallback| Options {
+        max_distance_meters: Some(max_distance_meters),
+        max_distance_meters_per_mode: HashMap::from([("foot".to_string(), 1500.0)]),
+        max_attempts: 100,
+        retry_fallback,
+        ..Default::default()
+    };
+
+    // Zones far apart can't have trips that short, which is detected before sampling
+    let err = disaggregate(
+        "data/od.csv",
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(10.0, RetryFallback::Error),
+        |_| Ok(()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No points from S02001616 to S02001620 can be between 1 and 10 meters apart"
+    );
+
+    let mut output = Vec::new();
+    let summary = disaggregate(
+        "data/od.csv",
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(8000.0, RetryFallback::Drop),
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+    let input_trips: f64 = sum_trips_input(
+        "data/od.csv",
+        &[
+            "all",
+            "from_home",
+            "train",
+            "bus",
+            "car_driver",
+            "car_passenger",
+            "bicycle",
+            "foot",
+            "other",
+        ],
+    )
+    .values()
+    .sum();
+    assert_eq!(output.len() + summary.trips_dropped, input_trips as usize);
+    assert!(summary.trips_dropped > 0);
+    for feature in output {
+        let line: LineString<f64> = feature.geometry.clone().unwrap().try_into().unwrap();
+        let distance = Point::from(line.0[0]).haversine_distance(&Point::from(line.0[1]));
+        let max = if feature.property("mode").unwrap() == "foot" {
+            1500.0
+        } else {
+            8000.0
+        };
+        assert!(
+            (1.0..=max).contains(&distance),
+            "Trip is {distance} meters long: {feature:?}"
+        );
+    }
+}
+
+#[test]
+fn test_match_distances() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let run = |distance_candidates| {
+        let options = Options {
+            distance_key: Some("distance".to_string()),
+            distance_candidates,
+            ..Default::default()
+        };
+        let mut modes = HashSet::new();
+        let summary = disaggregate(
+            "data/od_distances.csv",
+            &zones,
+            &mut StdRng::seed_from_u64(42),
+            options,
+            |feature| {
+                modes.insert(
+                    feature
+                        .property("mode")
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                );
+                Ok(())
+            },
+        )
+        .unwrap();
+        // The distance column isn't a mode
+        assert_eq!(
+            modes,
+            HashSet::from(["foot".to_string(), "bicycle".to_string()])
+        );
+        summary.distances.unwrap()
+    };
+
+    // With one candidate, trips are sampled without considering the target
+    let unmatched = run(1);
+    let matched = run(20);
+    // The third row has an empty distance, so its trips are sampled normally and not summarized
+    assert_eq!(matched.trips, 75);
+    assert_eq!(matched.mean_target_meters, (1500.0 + 3000.0 + 2500.0) / 3.0);
+    assert!(
+        matched.mean_absolute_error_meters < unmatched.mean_absolute_error_meters / 2.0,
+        "Matching distances didn't help: {matched:?} vs {unmatched:?}"
+    );
+
+    let run_csv = |csv: &'static str| {
+        let options = Options {
+            distance_key: Some("distance".to_string()),
+            retry_fallback: RetryFallback::Error,
+            ..Default::default()
+        };
+        let mut num_trips = 0;
+        disaggregate(
+            OdInput::csv_reader(csv.as_bytes()),
+            &zones,
+            &mut StdRng::seed_from_u64(42),
+            options,
+            |_| {
+                num_trips += 1;
+                Ok(())
+            },
+        )
+        .map(|summary| (num_trips, summary.distances.unwrap()))
+    };
+
+    // Distances have to be numbers
+    for (csv, err) in [
+        (
+            "geo_code1,geo_code2,foot,distance\nS02001616,S02001620,1,far\n",
+            "The OD input has a non-numeric distance value far; set distance_key properly",
+        ),
+        (
+            "geo_code1,geo_code2,foot,distance\nS02001616,S02001620,1,-5\n",
+            "The OD input has a negative distance value -5; set distance_key properly",
+        ),
+    ] {
+        assert_eq!(run_csv(csv).unwrap_err().to_string(), err);
+    }
+
+    // A target distance much further than the zones are apart isn't an error, even with
+    // RetryFallback::Error. The furthest pairs found are used.
+    let (num_trips, distances) =
+        run_csv("geo_code1,geo_code2,foot,distance\nS02001616,S02001620,10,1000000\n").unwrap();
+    assert_eq!(num_trips, 10);
+    assert_eq!(distances.trips, 10);
+    assert!(distances.mean_achieved_meters < 10_000.0, "{distances:?}");
+}
+
+#[test]
+fn test_geojson_metadata() {
+    let metadata = Metadata {
+        rng_algorithm: "chacha8".to_string(),
+        rng_seed: 42,
+    };
+    let mut writer = GeoJsonWriter::new(Vec::new(), Some(&metadata)).unwrap();
+    let feature = crate::to_geojson(
+        Point::new(1.0, 2.0),
+        Point::new(3.0, 4.0),
+        serde_json::Map::new(),
+    );
+    writer.write_feature(&feature).unwrap();
+    writer.write_feature(&feature).unwrap();
+    let bytes = writer.finish().unwrap();
+
+    let collection: geojson::FeatureCollection = String::from_utf8(bytes).unwrap().parse().unwrap();
+    assert_eq!(collection.features, vec![feature.clone(), feature]);
+    assert_eq!(
+        collection.foreign_members.unwrap()["odjitter"],
+        metadata.to_json()
+    );
+}
+
+#[test]
+fn test_geojson_seq_output() {
+    let feature = crate::to_geojson(
+        Point::new(1.0, 2.0),
+        Point::new(3.0, 4.0),
+        serde_json::Map::new(),
+    );
+    for record_separator in [false, true] {
+        let mut writer = GeoJsonSeqWriter::new(Vec::new(), record_separator);
+        writer.write_feature(&feature).unwrap();
+        writer.write_feature(&feature).unwrap();
+        let text = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            let line = if record_separator {
+                line.strip_prefix('\x1e').unwrap()
+            } else {
+                line
+            };
+            assert_eq!(line.parse::<Feature>().unwrap(), feature);
+        }
+    }
+}
+
+#[test]
+fn test_geoparquet_output() {
+    use arrow_array::{Array, BinaryArray, Float64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let metadata = Metadata {
+        rng_algorithm: "chacha8".to_string(),
+        rng_seed: 42,
+    };
+    let mut features = Vec::new();
+    for (idx, mode) in [Some("foot"), None, Some("bus")].into_iter().enumerate() {
+        let mut properties = Map::new();
+        properties.insert("count".to_string(), Value::from(idx as f64 + 0.5));
+        if let Some(mode) = mode {
+            properties.insert("mode".to_string(), Value::from(mode));
+        }
+        features.push(crate::to_geojson(
+            Point::new(idx as f64, 2.0),
+            Point::new(3.0, 4.0),
+            properties,
+        ));
+    }
+
+    let path = std::env::temp_dir().join("odjitter_test_output.parquet");
+    let mut writer = GeoParquetWriter::new(
+        fs_err::File::create(&path).unwrap(),
+        Some(&metadata),
+        &Crs::wgs84(),
+    )
+    .unwrap();
+    for feature in &features {
+        writer.write_feature(feature).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let builder =
+        ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+    let key_values = builder
+        .metadata()
+        .file_metadata()
+        .key_value_metadata()
+        .unwrap()
+        .clone();
+    let get_key = |key: &str| -> Value {
+        let kv = key_values.iter().find(|kv| kv.key == key).unwrap();
+        serde_json::from_str(kv.value.as_ref().unwrap()).unwrap()
+    };
+    assert_eq!(get_key("odjitter"), metadata.to_json());
+    assert_eq!(get_key("geo")["primary_column"], "geometry");
+    assert_eq!(
+        get_key("geo")["columns"]["geometry"]["bbox"],
+        serde_json::json!([0.0, 2.0, 3.0, 4.0])
+    );
+
+    let batches = builder
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.num_rows(), 3);
+
+    let count = batch
+        .column_by_name("count")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap();
+    assert_eq!(count.values().to_vec(), vec![0.5, 1.5, 2.5]);
+    let mode = batch
+        .column_by_name("mode")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(
+        mode.iter().collect::<Vec<_>>(),
+        vec![Some("foot"), None, Some("bus")]
+    );
+
+    // Decode the WKB of the second LineString
+    let geometry = batch
+        .column_by_name("geometry")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<BinaryArray>()
+        .unwrap();
+    let wkb = geometry.value(1);
+    assert_eq!(wkb[0], 1);
+    assert_eq!(u32::from_le_bytes(wkb[1..5].try_into().unwrap()), 2);
+    assert_eq!(u32::from_le_bytes(wkb[5..9].try_into().unwrap()), 2);
+    let coords: Vec<f64> = wkb[9..]
+        .chunks(8)
+        .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    assert_eq!(coords, vec![1.0, 2.0, 3.0, 4.0]);
+
+    // The schema comes from the first batch of features, so a new property later is an error
+    let feature = |key: &str| {
+        let mut properties = Map::new();
+        properties.insert(key.to_string(), Value::from(1.0));
+        crate::to_geojson(Point::new(0.0, 0.0), Point::new(1.0, 1.0), properties)
+    };
+    let mut writer = GeoParquetWriter::new(Vec::new(), None, &Crs::wgs84()).unwrap();
+    for _ in 0..10_000 {
+        writer.write_feature(&feature("count")).unwrap();
+    }
+    writer.write_feature(&feature("other")).unwrap();
+    assert_eq!(
+        writer.finish().unwrap_err().to_string(),
+        "A feature has a other property, but no feature in the first batch did"
+    );
+
+    // There's no way to record other CRSs
+    let crs = Crs::parse("EPSG:27700").unwrap();
+    assert_eq!(
+        GeoParquetWriter::new(Vec::new(), None, &crs)
+            .err()
+            .unwrap()
+            .to_string(),
+        "GeoParquet output has to be in WGS84, not EPSG:27700; reproject the output first"
+    );
+}
+
+#[test]
+fn test_od_records() {
+    let mut zones = HashMap::new();
+    zones.insert(
+        "A".to_string(),
+        MultiPolygon::from(Rect::new((0.0, 0.0), (0.01, 0.01)).to_polygon()),
+    );
+    zones.insert(
+        "B".to_string(),
+        MultiPolygon::from(Rect::new((0.02, 0.0), (0.03, 0.01)).to_polygon()),
+    );
+    let records = vec![OdRecord {
+        origin: "A".to_string(),
+        destination: "B".to_string(),
+        columns: vec![
+            ("foot".to_string(), OdValue::Count(3.0)),
+            ("car".to_string(), OdValue::Count(1.5)),
+            (
+                "name".to_string(),
+                OdValue::Attribute(Value::from("school run")),
+            ),
+            ("year".to_string(), OdValue::Attribute(Value::from(2011))),
+            // Given as a count, but it's the distance column
+            ("distance".to_string(), OdValue::Count(2500.0)),
+        ],
+    }];
+    let options = || Options {
+        origin_key: "from".to_string(),
+        destination_key: "to".to_string(),
+        distance_key: Some("distance".to_string()),
+        ..Default::default()
+    };
+
+    let mut output = Vec::new();
+    jitter(
+        records.clone(),
+        &zones,
+        1,
+        "foot".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options(),
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(output.len(), 3);
+    for feature in &output {
+        let properties = feature.properties.as_ref().unwrap();
+        // The zones come first, then the columns in order
+        assert_eq!(
+            properties.keys().collect::<Vec<_>>(),
+            vec!["from", "to", "foot", "car", "name", "year", "distance"]
+        );
+        assert_eq!(properties["from"], "A");
+        assert_eq!(properties["foot"], 1.0);
+        assert_eq!(properties["car"], 0.5);
+        // Attributes aren't scaled, even if they're numeric
+        assert_eq!(properties["name"], "school run");
+        assert_eq!(properties["year"], 2011);
+        assert_eq!(properties["distance"], 2500.0);
+    }
+
+    let mut modes = Vec::new();
+    disaggregate(
+        records,
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(),
+        |feature| {
+            modes.push(
+                feature
+                    .property("mode")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+            Ok(())
+        },
+    )
+    .unwrap();
+    // The distance isn't a mode
+    assert_eq!(modes, vec!["foot", "foot", "foot", "car"]);
+}
+
+#[test]
+fn test_projected_crs() {
+    use geo::algorithm::map_coords::MapCoords;
+
+    let bng = Crs::parse("EPSG:27700").unwrap();
+    assert_eq!(Crs::parse("urn:ogc:def:crs:EPSG::27700").unwrap(), bng);
+    assert!(!bng.is_geographic());
+    assert!(Crs::parse("not a crs").is_err());
+    // Equivalent proj4 strings are the same CRS, no matter how they're written
+    for proj4 in [
+        "+proj=tmerc +lat_0=49 +lon_0=-2 +k=0.9996012717 +x_0=400000 +y_0=-100000 +ellps=airy +towgs84=446.448,-125.157,542.06,0.15,0.247,0.842,-20.489 +units=m +no_defs +type=crs",
+        "+proj=tmerc +lat_0=49.0 +lon_0=-2 +k=0.9996012717 +x_0=400000 +y_0=-100000 +datum=OSGB36",
+    ] {
+        assert_eq!(Crs::parse(proj4).unwrap(), bng, "{proj4}");
+    }
+    assert_eq!(
+        Crs::parse("+proj=longlat +ellps=WGS84 +towgs84=0,0,0 +no_defs").unwrap(),
+        Crs::wgs84()
+    );
+    assert_ne!(Crs::parse("EPSG:32630").unwrap(), bng);
+    // Edinburgh Castle
+    let castle = bng.from_wgs84(Point::new(-3.2008, 55.9486)).unwrap();
+    assert!(castle.euclidean_distance(&Point::new(325100.0, 673500.0)) < 100.0);
+    assert_eq!(
+        crate::read_crs("data/zones.geojson").unwrap(),
+        Some(Crs::wgs84())
+    );
+    assert_eq!(
+        crate::read_crs("data/population.tif").unwrap(),
+        Some(Crs::wgs84())
+    );
+    // A CRS that can't be identified isn't mistaken for WGS84
+    let shp_path = std::env::temp_dir().join("odjitter_test_unknown_crs.shp");
+    let prj_path = shp_path.with_extension("prj");
+    std::fs::write(
+        &prj_path,
+        "PROJCS[\"ETRS_1989_UTM_Zone_32N\",GEOGCS[\"GCS_ETRS_1989\"]]",
+    )
+    .unwrap();
+    assert_eq!(
+        crate::read_crs(&shp_path.to_string_lossy())
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "Couldn't determine the CRS of {}; pass --crs",
+            prj_path.display()
+        )
+    );
+
+    // Project the zones to the British National Grid
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let projected_zones: HashMap<String, MultiPolygon<f64>> = zones
+        .iter()
+        .map(|(name, polygon)| {
+            let polygon = polygon.map_coords(|c| bng.from_wgs84(Point::from(c)).unwrap().into());
+            (name.clone(), polygon)
+        })
+        .collect();
+
+    let options = |reproject_to_wgs84| Options {
+        min_distance_meters: 500.0,
+        retry_fallback: RetryFallback::Drop,
+        crs: bng.clone(),
+        reproject_to_wgs84,
+        ..Default::default()
+    };
+    let run = |reproject_to_wgs84| {
+        let mut lines = Vec::new();
+        jitter(
+            "data/od.csv",
+            &projected_zones,
+            10,
+            "all".to_string(),
+            &mut StdRng::seed_from_u64(42),
+            options(reproject_to_wgs84),
+            |feature| {
+                let geometry: geo_types::Geometry<f64> =
+                    feature.geometry.unwrap().try_into().unwrap();
+                lines.push(LineString::try_from(geometry).unwrap());
+                Ok(())
+            },
+        )
+        .unwrap();
+        lines
+    };
+
+    // Distances are measured in meters on the grid
+    let projected = run(false);
+    assert!(!projected.is_empty());
+    for line in &projected {
+        assert!(
+            line.points()
+                .next()
+                .unwrap()
+                .euclidean_distance(&line.points().nth(1).unwrap())
+                >= 500.0
+        );
+    }
+
+    // The same trips can be transformed back to WGS84
+    let reprojected = run(true);
+    assert_eq!(projected.len(), reprojected.len());
+    for (projected, reprojected) in projected.iter().zip(reprojected) {
+        for (pt1, pt2) in projected.points().zip(reprojected.points()) {
+            let expected = bng.to_wgs84(pt1).unwrap();
+            assert!(expected.euclidean_distance(&pt2) < 1e-9);
+        }
+        let grid_distance = projected.0[0].euclidean_distance(&projected.0[1]);
+        let distance = Point::from(reprojected.0[0]).haversine_distance(&reprojected.0[1].into());
+        assert!((grid_distance - distance).abs() / distance < 0.01);
+    }
+}
+
+#[test]
+fn test_trip_iterators() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = || Options::default();
+
+    let mut features = Vec::new();
+    let callback_summary = jitter(
+        "data/od.csv",
+        &zones,
+        10,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options(),
+        |feature| {
+            features.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    // The iterator produces the same output as the callback
+    let mut trips = jitter_trips(
+        "data/od.csv",
+        &zones,
+        10,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options(),
+    )
+    .unwrap();
+    let mut trip_features = Vec::new();
+    for trip in &mut trips {
+        let trip = trip.unwrap();
+        assert_eq!(trip.properties["geo_code1"], trip.origin_zone.as_str());
+        assert_eq!(trip.properties["geo_code2"], trip.destination_zone.as_str());
+        assert!(zones[&trip.origin_zone].contains(&trip.origin));
+        assert!(zones[&trip.destination_zone].contains(&trip.destination));
+        trip_features.push(Feature::from(trip));
+    }
+    assert_eq!(trip_features, features);
+    assert_eq!(
+        format!("{:?}", trips.finish().unwrap()),
+        format!("{callback_summary:?}")
+    );
+
+    // Stopping early works
+    let trips = disaggregate_trips(
+        "data/od.csv",
+        &zones,
+        &mut StdRng::seed_from_u64(42),
+        options(),
+    )
+    .unwrap();
+    let modes = trips
+        .take(5)
+        .map(|trip| trip.unwrap().properties["mode"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(modes.len(), 5);
+    assert!(modes.iter().all(|mode| mode.is_string()));
+}
+
+#[test]
+fn test_destination_zones() {
+    // The same names refer to different places for origins and destinations
+    let mut origin_zones = HashMap::new();
+    origin_zones.insert(
+        "A".to_string(),
+        MultiPolygon::from(Rect::new((0.0, 0.0), (0.01, 0.01)).to_polygon()),
+    );
+    let mut destination_zones = HashMap::new();
+    destination_zones.insert(
+        "A".to_string(),
+        MultiPolygon::from(Rect::new((0.02, 0.0), (0.03, 0.01)).to_polygon()),
+    );
+    destination_zones.insert(
+        "B".to_string(),
+        MultiPolygon::from(Rect::new((0.04, 0.0), (0.05, 0.01)).to_polygon()),
+    );
+    let record = |origin: &str, destination: &str| OdRecord {
+        origin: origin.to_string(),
+        destination: destination.to_string(),
+        columns: vec![("all".to_string(), OdValue::Count(5.0))],
+    };
+    let records = vec![record("A", "A"), record("A", "B"), record("B", "A")];
     let options = Options {
-        subsample_origin: Subsample::RandomPoints,
-        subsample_destination: Subsample::RandomPoints,
-        origin_key: "geo_code1".to_string(),
-        destination_key: "geo_code2".to_string(),
-        min_distance_meters: 1.0,
-        deduplicate_pairs: false,
+        origin_key: "from".to_string(),
+        destination_key: "to".to_string(),
+        unknown_zones: UnknownZones::Skip,
+        ..Default::default()
+    };
+
+    let mut trips = jitter_trips(
+        records,
+        Zones::new(&origin_zones, &destination_zones),
+        1,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options,
+    )
+    .unwrap();
+    let mut count = 0;
+    for trip in &mut trips {
+        let trip = trip.unwrap();
+        assert!(origin_zones[&trip.origin_zone].contains(&trip.origin));
+        assert!(destination_zones[&trip.destination_zone].contains(&trip.destination));
+        count += 1;
+    }
+    assert_eq!(count, 10);
+    // B is only a destination zone
+    let summary = trips.finish().unwrap();
+    assert_eq!(summary.rows_skipped, 1);
+    assert_eq!(summary.unknown_zones.keys().collect::<Vec<_>>(), vec!["B"]);
+}
+
+#[test]
+fn test_destination_points() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let schools = load_points("data/schools.geojson", "id").unwrap();
+    assert_eq!(schools.len(), 31);
+
+    let record = |origin: &str, destination: &str| OdRecord {
+        origin: origin.to_string(),
+        destination: destination.to_string(),
+        columns: vec![
+            ("walk".to_string(), OdValue::Count(3.0)),
+            ("car".to_string(), OdValue::Count(2.0)),
+        ],
+    };
+    // Zone IDs still work for destinations
+    let records = vec![
+        record("S02001616", "4982227"),
+        record("S02001620", "4990581"),
+        record("S02001620", "S02001616"),
+    ];
+    let options = Options {
+        origin_key: "from".to_string(),
+        destination_key: "to".to_string(),
+        ..Default::default()
+    };
+    let mut zones_and_schools = Zones::from(&zones);
+    zones_and_schools.destination_points = Some(&schools);
+
+    let trips = disaggregate_trips(
+        records,
+        zones_and_schools,
+        &mut StdRng::seed_from_u64(42),
+        options,
+    )
+    .unwrap()
+    .collect::<anyhow::Result<Vec<_>>>()
+    .unwrap();
+    assert_eq!(trips.len(), 15);
+    for trip in trips {
+        assert!(zones[&trip.origin_zone].contains(&trip.origin));
+        if let Some(school) = schools.get(&trip.destination_zone) {
+            // Trips end exactly at the school
+            assert_eq!(trip.destination, *school);
+        } else {
+            assert!(zones[&trip.destination_zone].contains(&trip.destination));
+        }
+    }
+}
+
+#[test]
+fn test_od_inputs() {
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let run = |input: OdInput| {
+        let options = Options {
+            // Large enough to need a feasibility check
+            min_distance_meters: 100.0,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut output = Vec::new();
+        jitter(
+            input,
+            &zones,
+            10,
+            "all".to_string(),
+            &mut rng,
+            options,
+            |feature| {
+                output.push(feature);
+                Ok(())
+            },
+        )
+        .unwrap();
+        output
     };
+    let expected = run(OdInput::csv("data/od.csv"));
+
+    let mut reader = csv::Reader::from_path("data/od.csv").unwrap();
+    let header: Vec<String> = reader
+        .headers()
+        .unwrap()
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let records: Vec<csv::StringRecord> = reader.records().map(|rec| rec.unwrap()).collect();
+
+    // Zone IDs as strings, and everything else as numbers
+    let columns: Vec<ArrayRef> = header
+        .iter()
+        .enumerate()
+        .map(|(idx, key)| -> ArrayRef {
+            if key.starts_with("geo_code") {
+                Arc::new(StringArray::from_iter_values(
+                    records.iter().map(|rec| rec[idx].to_string()),
+                ))
+            } else {
+                Arc::new(Float64Array::from_iter_values(
+                    records.iter().map(|rec| rec[idx].parse::<f64>().unwrap()),
+                ))
+            }
+        })
+        .collect();
+    let batch = RecordBatch::try_from_iter(header.iter().zip(columns)).unwrap();
+    let parquet_path = std::env::temp_dir().join("odjitter_test_od.parquet");
+    let mut writer = ArrowWriter::try_new(
+        std::fs::File::create(&parquet_path).unwrap(),
+        batch.schema(),
+        None,
+    )
+    .unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+    assert_eq!(run(OdInput::from_path(&parquet_path)), expected);
+
+    let features: Vec<Feature> = records
+        .iter()
+        .map(|rec| {
+            let mut properties = Map::new();
+            for (key, value) in header.iter().zip(rec.iter()) {
+                let value = match value.parse::<f64>() {
+                    Ok(x) if !key.starts_with("geo_code") => Value::from(x),
+                    _ => Value::from(value),
+                };
+                properties.insert(key.clone(), value);
+            }
+            Feature {
+                bbox: None,
+                geometry: None,
+                id: None,
+                properties: Some(properties),
+                foreign_members: None,
+            }
+        })
+        .collect();
+    let geojson_path = std::env::temp_dir().join("odjitter_test_od.geojson");
+    std::fs::write(
+        &geojson_path,
+        geojson::FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        }
+        .to_string(),
+    )
+    .unwrap();
+    assert_eq!(run(OdInput::from_path(&geojson_path)), expected);
+
+    let bytes = std::fs::read("data/od.csv").unwrap();
+    assert_eq!(
+        run(OdInput::csv_reader(std::io::Cursor::new(bytes))),
+        expected
+    );
+}
+
+#[test]
+fn test_zone_formats() {
+    use geo::algorithm::area::Area;
+
+    let expected = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let expected_weight: f64 = scrape_points("data/zones.geojson", Some("TotPop2011".to_string()))
+        .unwrap()
+        .into_iter()
+        .map(|pt| pt.weight)
+        .sum();
+
+    for path in ["data/zones.fgb", "data/zones.shp", "data/zones.gpkg"] {
+        let zones = load_zones(path, "InterZone").unwrap();
+        assert_eq!(
+            zones.keys().collect::<HashSet<_>>(),
+            expected.keys().collect::<HashSet<_>>(),
+            "{path}"
+        );
+        for (name, polygon) in &zones {
+            let epsilon = 1e-12;
+            assert!(
+                (polygon.unsigned_area() - expected[name].unsigned_area()).abs() < epsilon,
+                "{name} in {path} has a different area"
+            );
+        }
+
+        // Properties keep their numeric type
+        let weight: f64 = scrape_points(path, Some("TotPop2011".to_string()))
+            .unwrap()
+            .into_iter()
+            .map(|pt| pt.weight)
+            .sum();
+        assert!((weight - expected_weight).abs() < 1e-6, "{path}");
+    }
+
+    // A zone without a geometry is an error, not a panic
+    let path = std::env::temp_dir().join("odjitter_test_missing_geometry.geojson");
+    fs_err::write(
+        &path,
+        r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":null,"properties":{"InterZone":"empty"}}]}"#,
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+    assert_eq!(
+        load_zones(path, "InterZone").unwrap_err().to_string(),
+        format!("Zone empty in {path} doesn't have a geometry")
+    );
+}
+
+#[test]
+fn test_csv_output() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let options = Options::default();
     let mut rng = StdRng::seed_from_u64(42);
-    let mut output = Vec::new();
-    disaggregate("data/od.csv", &zones, &mut rng, options, |feature| {
-        output.push(feature);
-        Ok(())
-    })
+    let mut features = Vec::new();
+    jitter(
+        "data/od.csv",
+        &zones,
+        100,
+        "all".to_string(),
+        &mut rng,
+        options,
+        |feature| {
+            features.push(feature);
+            Ok(())
+        },
+    )
     .unwrap();
 
-    // Note "all" has no special meaning to the disaggregate call. The user should probably remove
-    // it from the input or ignore it in the output.
-    let input_sums = sum_trips_input("data/od.csv", &["all", "car_driver", "foot"]);
-    let mut sums_per_mode: HashMap<String, usize> = HashMap::new();
-    for feature in output {
-        let mode = feature
-            .property("mode")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string();
-        *sums_per_mode.entry(mode).or_insert(0) += 1;
+    let mut writer = CsvWriter::new(Vec::new(), CsvGeometry::Coordinates, &Crs::wgs84());
+    for feature in &features {
+        writer.write_feature(feature).unwrap();
     }
-    for (mode, input_sum) in input_sums {
-        let output_sum = sums_per_mode[&mode];
-        assert!(
-            input_sum as usize == output_sum,
-            "Number of {} trips in input {} and disaggregated output {} don't match",
-            mode,
-            input_sum,
-            output_sum,
+    let bytes = writer.finish().unwrap();
+    let mut reader = csv::Reader::from_reader(bytes.as_slice());
+    let input_header = csv::Reader::from_path("data/od.csv")
+        .unwrap()
+        .headers()
+        .unwrap()
+        .clone();
+    // The geometry comes first, then the input columns in their original order
+    assert_eq!(
+        reader.headers().unwrap().iter().collect::<Vec<_>>(),
+        ["o_lon", "o_lat", "d_lon", "d_lat"]
+            .into_iter()
+            .chain(input_header.iter())
+            .collect::<Vec<_>>()
+    );
+    let records = reader.records().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(records.len(), features.len());
+    for (record, feature) in records.iter().zip(&features) {
+        let line: LineString<f64> = feature.geometry.clone().unwrap().try_into().unwrap();
+        let coords: Vec<f64> = (0..4).map(|idx| record[idx].parse().unwrap()).collect();
+        assert_eq!(
+            coords,
+            vec![line.0[0].x, line.0[0].y, line.0[1].x, line.0[1].y]
+        );
+        assert_eq!(
+            &record[4],
+            feature.property("geo_code1").unwrap().as_str().unwrap()
         );
+        assert_eq!(
+            record[6].parse::<f64>().unwrap(),
+            feature.property("all").unwrap().as_f64().unwrap()
+        );
+    }
+
+    let mut writer = CsvWriter::new(Vec::new(), CsvGeometry::Wkt, &Crs::wgs84());
+    let mut properties = Map::new();
+    properties.insert("mode".to_string(), Value::from("foot"));
+    writer
+        .write_feature(&crate::to_geojson(
+            Point::new(1.5, 2.0),
+            Point::new(-3.0, 4.25),
+            properties,
+        ))
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(writer.finish().unwrap()).unwrap(),
+        "geometry,mode\n\"LINESTRING(1.5 2, -3 4.25)\",foot\n"
+    );
+
+    // Projected coordinates aren't longitude and latitude
+    let crs = Crs::parse("EPSG:27700").unwrap();
+    let writer = CsvWriter::new(Vec::new(), CsvGeometry::Coordinates, &crs);
+    assert_eq!(
+        String::from_utf8(writer.finish().unwrap()).unwrap(),
+        "o_x,o_y,d_x,d_y\n"
+    );
+}
+
+#[test]
+fn test_indexed_flatgeobuf() {
+    use flatgeobuf::{FallibleStreamingIterator, FeatureProperties, FgbReader};
+
+    let metadata = Metadata {
+        rng_algorithm: "chacha8".to_string(),
+        rng_seed: 42,
+    };
+    // Short lines along a diagonal
+    let features: Vec<Feature> = (0..100)
+        .map(|idx| {
+            let mut properties = Map::new();
+            properties.insert("idx".to_string(), Value::from(idx as f64));
+            let x = idx as f64 * 0.01;
+            crate::to_geojson(
+                Point::new(x, x),
+                Point::new(x + 0.005, x + 0.005),
+                properties,
+            )
+        })
+        .collect();
+
+    let mut bytes = Vec::new();
+    let mut writer = FlatGeobufWriter::new(Some(&metadata), true, &Crs::wgs84()).unwrap();
+    for feature in &features {
+        writer.write_feature(feature).unwrap();
+    }
+    writer.finish(&mut bytes).unwrap();
+
+    let mut cursor = std::io::Cursor::new(bytes);
+    let reader = FgbReader::open(&mut cursor).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Value>(reader.header().metadata().unwrap()).unwrap(),
+        metadata.to_json()
+    );
+    let mut selected = reader.select_bbox(0.2, 0.2, 0.3, 0.3).unwrap();
+    let mut actual = Vec::new();
+    while let Some(feature) = selected.next().unwrap() {
+        actual.push(feature.property::<f64>("idx").unwrap() as usize);
     }
+    actual.sort();
+    // Lines 20 to 30 overlap the bounding box; line 19 ends at 0.195
+    assert_eq!(actual, (20..=30).collect::<Vec<_>>());
+
+    // The CRS is recorded in the header
+    let path = std::env::temp_dir().join("odjitter_test_output.fgb");
+    let mut writer =
+        FlatGeobufWriter::new(None, false, &Crs::parse("EPSG:27700").unwrap()).unwrap();
+    writer.write_feature(&features[0]).unwrap();
+    // The columns come from the first feature
+    let mut properties = Map::new();
+    properties.insert("mode".to_string(), Value::from("foot"));
+    assert_eq!(
+        writer
+            .write_feature(&crate::to_geojson(
+                Point::new(0.0, 0.0),
+                Point::new(1.0, 1.0),
+                properties
+            ))
+            .unwrap_err()
+            .to_string(),
+        "A feature has a mode property, but the first feature didn't"
+    );
+    writer
+        .finish(&mut fs_err::File::create(&path).unwrap())
+        .unwrap();
+    assert_eq!(
+        read_crs(path.to_str().unwrap()).unwrap(),
+        Some(Crs::parse("EPSG:27700").unwrap())
+    );
 }
 
 #[test]
@@ -180,10 +1582,8 @@ fn test_deduplicate_pairs() {
         let options = Options {
             subsample_origin: Subsample::WeightedPoints(subpoints.clone()),
             subsample_destination: Subsample::WeightedPoints(subpoints.clone()),
-            origin_key: "geo_code1".to_string(),
-            destination_key: "geo_code2".to_string(),
-            min_distance_meters: 1.0,
             deduplicate_pairs,
+            ..Default::default()
         };
         let mut rng = StdRng::seed_from_u64(42);
         let mut output = Vec::new();
@@ -230,6 +1630,642 @@ fn test_deduplicate_pairs() {
     }
 }
 
+#[test]
+fn test_sample_weighted_points() {
+    let square = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        MultiPolygon::new(vec![Polygon::new(
+            LineString::from(vec![(x1, y1), (x2, y1), (x2, y2), (x1, y2), (x1, y1)]),
+            Vec::new(),
+        )])
+    };
+    let zones = HashMap::from([
+        ("a".to_string(), square(0.0, 0.0, 100.0, 100.0)),
+        ("b".to_string(), square(200.0, 0.0, 300.0, 100.0)),
+    ]);
+    // Every point in zone a has a different weight, and one is never used
+    let point = |x, weight| WeightedPoint {
+        point: Point::new(x, 50.0),
+        weight,
+    };
+    let options = Options {
+        subsample_origin: Subsample::WeightedPoints(vec![
+            point(10.0, 0.0),
+            point(20.0, 1.0),
+            point(30.0, 99.0),
+        ]),
+        crs: Crs::parse("EPSG:27700").unwrap(),
+        ..Default::default()
+    };
+    let records = vec![OdRecord {
+        origin: "a".to_string(),
+        destination: "b".to_string(),
+        columns: vec![("all".to_string(), OdValue::Count(10_000.0))],
+    }];
+    let mut per_point = HashMap::new();
+    for trip in jitter_trips(
+        records,
+        &zones,
+        1,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options,
+    )
+    .unwrap()
+    {
+        *per_point
+            .entry(trip.unwrap().origin.x() as usize)
+            .or_insert(0) += 1;
+    }
+
+    assert_eq!(per_point.get(&10), None);
+    let heavy = per_point[&30] as f64 / 10_000.0;
+    assert!(
+        (heavy - 0.99).abs() < 0.005,
+        "The heaviest point was picked {heavy} of the time, but expected 0.99"
+    );
+    assert_eq!(per_point[&20] + per_point[&30], 10_000);
+}
+
+#[test]
+fn test_sample_along_lines() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let lines = scrape_lines("data/road_network.geojson", None).unwrap();
+    let vertices: HashSet<_> = scrape_points("data/road_network.geojson", None)
+        .unwrap()
+        .into_iter()
+        .map(|pt| hashify_point(pt.point))
+        .collect();
+
+    let options = Options {
+        subsample_origin: Subsample::WeightedLines(lines.clone()),
+        subsample_destination: Subsample::WeightedLines(lines.clone()),
+        ..Default::default()
+    };
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut output = Vec::new();
+    jitter(
+        "data/od.csv",
+        &zones,
+        10,
+        "all".to_string(),
+        &mut rng,
+        options,
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    let mut num_vertices = 0;
+    let mut num_endpoints = 0;
+    for feature in &output {
+        if let Some(geojson::Value::LineString(ls)) =
+            feature.geometry.as_ref().map(|geom| &geom.value)
+        {
+            for pt in ls {
+                let pt = Point::new(pt[0], pt[1]);
+                num_endpoints += 1;
+                if vertices.contains(&hashify_point(pt)) {
+                    num_vertices += 1;
+                }
+                let on_road = lines
+                    .iter()
+                    .any(|line| pt.euclidean_distance(&line.line_string) < 1e-9);
+                assert!(on_road, "Endpoint {:?} isn't on any road", pt);
+            }
+        } else {
+            panic!("Output geometry isn't a LineString: {:?}", feature.geometry);
+        }
+    }
+    // Positions along the lines should almost never land exactly on a vertex
+    assert!(
+        num_vertices * 10 < num_endpoints,
+        "{num_vertices} of {num_endpoints} endpoints are road vertices"
+    );
+}
+
+#[test]
+fn test_clip_subpoints_to_zone() {
+    // Zone a has a hole in the middle. One road crosses the zone and the hole. In zone b, one
+    // building crosses the boundary, and another building with the same area inside the zone is
+    // entirely inside. Empty geometries are ignored.
+    let square = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        LineString::from(vec![(x1, y1), (x2, y1), (x2, y2), (x1, y2), (x1, y1)])
+    };
+    let zones = HashMap::from([
+        (
+            "a".to_string(),
+            MultiPolygon::new(vec![Polygon::new(
+                square(0.0, 0.0, 100.0, 100.0),
+                vec![square(40.0, 40.0, 60.0, 60.0)],
+            )]),
+        ),
+        (
+            "b".to_string(),
+            MultiPolygon::new(vec![Polygon::new(
+                square(200.0, 0.0, 300.0, 100.0),
+                Vec::new(),
+            )]),
+        ),
+    ]);
+    let options = Options {
+        subsample_origin: Subsample::WeightedLines(vec![
+            WeightedLineString {
+                line_string: vec![(-50.0, 50.0), (150.0, 50.0)].into(),
+                weight: 1.0,
+            },
+            WeightedLineString {
+                line_string: LineString::new(Vec::new()),
+                weight: 1.0,
+            },
+        ]),
+        subsample_destination: Subsample::WeightedPolygons(vec![
+            WeightedPolygon {
+                polygon: Polygon::new(square(250.0, 40.0, 350.0, 60.0), Vec::new()),
+                weight: 1.0,
+            },
+            WeightedPolygon {
+                polygon: Polygon::new(square(210.0, 70.0, 260.0, 90.0), Vec::new()),
+                weight: 1.0,
+            },
+            WeightedPolygon {
+                polygon: Polygon::new(LineString::new(Vec::new()), Vec::new()),
+                weight: 1.0,
+            },
+        ]),
+        crs: Crs::parse("EPSG:27700").unwrap(),
+        ..Default::default()
+    };
+    let records = vec![OdRecord {
+        origin: "a".to_string(),
+        destination: "b".to_string(),
+        columns: vec![("all".to_string(), OdValue::Count(100.0))],
+    }];
+    let trips = jitter_trips(
+        records,
+        &zones,
+        1,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options,
+    )
+    .unwrap()
+    .collect::<anyhow::Result<Vec<_>>>()
+    .unwrap();
+    assert_eq!(trips.len(), 100);
+    let mut in_crossing_building = 0;
+    for trip in trips {
+        let (o, d) = (trip.origin, trip.destination);
+        assert!(zones["a"].contains(&o), "{o:?} isn't in zone a");
+        assert_eq!(o.y(), 50.0);
+        assert!(o.x() > 0.0 && o.x() < 100.0 && !(o.x() > 40.0 && o.x() < 60.0));
+        assert!(zones["b"].contains(&d), "{d:?} isn't in zone b");
+        if d.x() > 250.0 && d.x() < 300.0 && d.y() > 40.0 && d.y() < 60.0 {
+            in_crossing_building += 1;
+        } else {
+            assert!(d.x() > 210.0 && d.x() < 260.0 && d.y() > 70.0 && d.y() < 90.0);
+        }
+    }
+    // Only the area inside the zone counts
+    assert!(
+        (35..=65).contains(&in_crossing_building),
+        "{in_crossing_building} of 100 destinations are in the building crossing the boundary"
+    );
+}
+
+#[test]
+fn test_sample_inside_polygons() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    // Pretend there's a small building around every school, and another one next to it with 0
+    // weight, which should never be used
+    let mut buildings = Vec::new();
+    for pt in scrape_points("data/schools.geojson", None).unwrap() {
+        for (shift, weight) in [(0.0, 1.0), (0.002, 0.0)] {
+            let offset = 0.0005;
+            let (x, y) = (pt.point.x() + shift, pt.point.y());
+            buildings.push(WeightedPolygon {
+                polygon: Rect::new((x - offset, y - offset), (x + offset, y + offset)).to_polygon(),
+                weight,
+            });
+        }
+    }
+
+    let options = Options {
+        subsample_destination: Subsample::WeightedPolygons(buildings.clone()),
+        origin_key: "origin".to_string(),
+        destination_key: "destination".to_string(),
+        ..Default::default()
+    };
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut output = Vec::new();
+    jitter(
+        "data/od_schools.csv",
+        &zones,
+        10,
+        "walk".to_string(),
+        &mut rng,
+        options,
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    for feature in &output {
+        if let Some(geojson::Value::LineString(ls)) =
+            feature.geometry.as_ref().map(|geom| &geom.value)
+        {
+            let pt = ls.last().unwrap();
+            let pt = Point::new(pt[0], pt[1]);
+            let in_building = buildings
+                .iter()
+                .any(|b| b.weight > 0.0 && b.polygon.contains(&pt));
+            assert!(
+                in_building,
+                "Destination {:?} isn't inside any building with a positive weight",
+                pt
+            );
+        } else {
+            panic!("Output geometry isn't a LineString: {:?}", feature.geometry);
+        }
+    }
+}
+
+#[test]
+fn test_sample_from_raster() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let raster = load_geotiff("data/population.tif").unwrap();
+    assert_eq!((raster.columns, raster.rows), (50, 40));
+    // The first row is nodata
+    assert!(raster.get(0, 1).is_nan());
+
+    let options = Options {
+        subsample_origin: Subsample::WeightedRaster(raster.clone()),
+        ..Default::default()
+    };
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut output = Vec::new();
+    disaggregate("data/od.csv", &zones, &mut rng, options, |feature| {
+        output.push(feature);
+        Ok(())
+    })
+    .unwrap();
+    assert!(!output.is_empty());
+
+    // Every origin must be in a cell with a positive value
+    for feature in &output {
+        if let Some(geojson::Value::LineString(ls)) =
+            feature.geometry.as_ref().map(|geom| &geom.value)
+        {
+            let pt = &ls[0];
+            let column = ((pt[0] - raster.top_left.x) / raster.cell_width).floor() as usize;
+            let row = ((raster.top_left.y - pt[1]) / raster.cell_height).floor() as usize;
+            let value = raster.get(row, column);
+            assert!(
+                value > 0.0,
+                "Origin {:?} is in a cell with value {}",
+                pt,
+                value
+            );
+        } else {
+            panic!("Output geometry isn't a LineString: {:?}", feature.geometry);
+        }
+    }
+}
+
+#[test]
+fn test_raster_cells_clipped_to_zones() {
+    // Four 100m cells. The bottom-right one is empty.
+    let raster = Raster {
+        top_left: (0.0, 200.0).into(),
+        cell_width: 100.0,
+        cell_height: 100.0,
+        columns: 2,
+        rows: 2,
+        values: vec![1.0, 2.0, 3.0, 0.0],
+    };
+    let square = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        MultiPolygon::new(vec![Polygon::new(
+            LineString::from(vec![(x1, y1), (x2, y1), (x2, y2), (x1, y2), (x1, y1)]),
+            Vec::new(),
+        )])
+    };
+    let zones = HashMap::from([
+        // Covers a quarter of every cell, but none of their centers
+        ("middle".to_string(), square(50.0, 50.0, 150.0, 150.0)),
+        // Smaller than one cell
+        ("small".to_string(), square(10.0, 110.0, 30.0, 130.0)),
+        // Only overlaps the empty cell
+        ("empty".to_string(), square(150.0, 10.0, 160.0, 20.0)),
+        // Covers the same area of the top two cells, with a notch crossing the edge between them
+        (
+            "notched".to_string(),
+            MultiPolygon::new(vec![Polygon::new(
+                LineString::from(vec![
+                    (0.0, 110.0),
+                    (200.0, 110.0),
+                    (200.0, 190.0),
+                    (120.0, 190.0),
+                    (120.0, 150.0),
+                    (80.0, 150.0),
+                    (80.0, 190.0),
+                    (0.0, 190.0),
+                    (0.0, 110.0),
+                ]),
+                Vec::new(),
+            )]),
+        ),
+    ]);
+    let options = Options {
+        subsample_origin: Subsample::WeightedRaster(raster.clone()),
+        subsample_destination: Subsample::WeightedRaster(raster),
+        crs: Crs::parse("EPSG:27700").unwrap(),
+        ..Default::default()
+    };
+    let record = |origin: &str, destination: &str| OdRecord {
+        origin: origin.to_string(),
+        destination: destination.to_string(),
+        columns: vec![("all".to_string(), OdValue::Count(600.0))],
+    };
+    let mut trips = jitter_trips(
+        vec![
+            record("middle", "small"),
+            record("empty", "middle"),
+            record("notched", "small"),
+        ],
+        &zones,
+        1,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        options,
+    )
+    .unwrap()
+    .collect::<anyhow::Result<Vec<_>>>()
+    .unwrap();
+    assert_eq!(trips.len(), 1800);
+    let notched_trips = trips.split_off(1200);
+
+    // Points in the middle zone are picked from each cell in proportion to its value
+    let mut per_cell = [0; 4];
+    for trip in &trips {
+        let (middle, other) = if trip.origin_zone == "middle" {
+            (trip.origin, trip.destination)
+        } else {
+            (trip.destination, trip.origin)
+        };
+        assert!(zones["middle"].contains(&middle));
+        assert!(zones[if trip.origin_zone == "middle" {
+            "small"
+        } else {
+            "empty"
+        }]
+        .contains(&other));
+        let column = (middle.x() / 100.0) as usize;
+        let row = ((200.0 - middle.y()) / 100.0) as usize;
+        per_cell[row * 2 + column] += 1;
+    }
+    assert_eq!(per_cell[3], 0);
+    for (cell, expected) in [(0, 1.0 / 6.0), (1, 2.0 / 6.0), (2, 3.0 / 6.0)] {
+        let fraction = per_cell[cell] as f64 / 1200.0;
+        assert!(
+            (fraction - expected).abs() < 0.05,
+            "Cell {cell} has {fraction} of the points, but expected {expected}"
+        );
+    }
+
+    // Points are never in the notch, and the right cell has twice the value
+    let mut in_right_cell = 0;
+    for trip in &notched_trips {
+        assert!(zones["notched"].contains(&trip.origin));
+        if trip.origin.x() > 100.0 {
+            in_right_cell += 1;
+        }
+    }
+    let fraction = in_right_cell as f64 / 600.0;
+    assert!(
+        (fraction - 2.0 / 3.0).abs() < 0.05,
+        "The right cell has {fraction} of the points, but expected 2/3"
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_output_independent_of_threads() {
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let subpoints = scrape_points("data/road_network.geojson", None).unwrap();
+
+    let mut outputs = Vec::new();
+    for num_threads in [1, 8] {
+        let options = Options {
+            subsample_origin: Subsample::WeightedPoints(subpoints.clone()),
+            subsample_destination: Subsample::WeightedPoints(subpoints.clone()),
+            deduplicate_pairs: true,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut output = Vec::new();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            jitter(
+                "data/od.csv",
+                &zones,
+                1,
+                "all".to_string(),
+                &mut rng,
+                options,
+                |feature| {
+                    output.push(feature);
+                    Ok(())
+                },
+            )
+        })
+        .unwrap();
+        outputs.push(output);
+    }
+    assert!(
+        outputs[0] == outputs[1],
+        "Output with 1 and 8 threads differs"
+    );
+}
+
+#[test]
+fn test_route() {
+    // A square with two ways from (0, 0) to (100, 100), plus a disconnected road
+    let line = |coords: Vec<(f64, f64)>, weight| WeightedLineString {
+        line_string: coords.into(),
+        weight,
+    };
+    let crs = Crs::parse("EPSG:27700").unwrap();
+    // An infinite weight closes a road
+    for (weight_east, weight_north, via) in [
+        (1.0, 2.0, (100.0, 0.0)),
+        (3.0, 2.0, (0.0, 100.0)),
+        (f64::INFINITY, 2.0, (0.0, 100.0)),
+    ] {
+        let mut network = RoadNetwork::from_lines(
+            vec![
+                line(vec![(0.0, 0.0), (100.0, 0.0), (100.0, 100.0)], weight_east),
+                line(vec![(0.0, 0.0), (0.0, 100.0), (100.0, 100.0)], weight_north),
+                line(vec![(500.0, 500.0), (600.0, 500.0)], 1.0),
+            ],
+            &crs,
+        )
+        .unwrap();
+
+        let route = network
+            .route(Point::new(-1.0, -1.0), Point::new(101.0, 101.0))
+            .unwrap();
+        let expected: LineString<f64> = vec![
+            (-1.0, -1.0),
+            (0.0, 0.0),
+            via,
+            (100.0, 100.0),
+            (101.0, 101.0),
+        ]
+        .into();
+        assert_eq!(route, expected);
+        assert!(network
+            .route(Point::new(0.0, 0.0), Point::new(510.0, 500.0))
+            .is_none());
+        // Earlier queries don't affect later ones
+        assert_eq!(
+            network.route(Point::new(-1.0, -1.0), Point::new(101.0, 101.0)),
+            Some(expected)
+        );
+    }
+
+    // Desire lines over a real network start and end at the same points, follow the roads and keep
+    // their properties
+    let mut network = RoadNetwork::load("data/road_network.geojson", None, &Crs::wgs84()).unwrap();
+    let vertices: HashSet<_> = scrape_points("data/road_network.geojson", None)
+        .unwrap()
+        .into_iter()
+        .map(|pt| hashify_point(pt.point))
+        .collect();
+    let zones = load_zones("data/zones.geojson", "InterZone").unwrap();
+    let mut output = Vec::new();
+    jitter(
+        "data/od.csv",
+        &zones,
+        50,
+        "all".to_string(),
+        &mut StdRng::seed_from_u64(42),
+        Options::default(),
+        |feature| {
+            output.push(feature);
+            Ok(())
+        },
+    )
+    .unwrap();
+    let mut num_routed = 0;
+    for desire_line in output {
+        let mut feature = desire_line.clone();
+        if network.route_feature(&mut feature).unwrap() {
+            num_routed += 1;
+        }
+        assert_eq!(feature.properties, desire_line.properties);
+        let straight: LineString<f64> = desire_line.geometry.unwrap().value.try_into().unwrap();
+        let route: LineString<f64> = feature.geometry.unwrap().value.try_into().unwrap();
+        assert_eq!(route.0.first(), straight.0.first());
+        assert_eq!(route.0.last(), straight.0.last());
+        for pt in &route.0[1..route.0.len() - 1] {
+            assert!(vertices.contains(&hashify_point((*pt).into())));
+        }
+    }
+    assert!(num_routed > 0);
+}
+
+#[test]
+fn test_overline() {
+    let line = |coords: Vec<(f64, f64)>, properties: Value| {
+        Ok(Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::from(&LineString::from(coords))),
+            id: None,
+            properties: properties.as_object().cloned(),
+            foreign_members: None,
+        })
+    };
+    // Two lines overlap in the middle, going in opposite directions. Numeric zone names aren't
+    // summed, and the name is dropped.
+    let input = || {
+        vec![
+            line(
+                vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)],
+                serde_json::json!({"geo_code1": "1", "geo_code2": "2", "foot": 2, "name": "x"}),
+            ),
+            line(
+                vec![(3.0, 0.0), (2.0, 0.0), (1.0, 0.0), (1.0, 1.0)],
+                serde_json::json!({"geo_code1": "3", "geo_code2": "4", "foot": 1, "bus": "5"}),
+            ),
+        ]
+    };
+    let mut options = OverlineOptions {
+        sum_keys: Vec::new(),
+        origin_key: "geo_code1".to_string(),
+        destination_key: "geo_code2".to_string(),
+        distance_key: None,
+    };
+    let simplify = |features: Vec<Feature>| -> Vec<(LineString<f64>, Value)> {
+        features
+            .into_iter()
+            .map(|feature| {
+                (
+                    feature.geometry.unwrap().value.try_into().unwrap(),
+                    Value::Object(feature.properties.unwrap()),
+                )
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        simplify(overline(input(), &options).unwrap()),
+        vec![
+            (
+                vec![(0.0, 0.0), (1.0, 0.0)].into(),
+                serde_json::json!({"foot": 2.0, "bus": 0.0})
+            ),
+            // Consecutive segments with the same sums are joined
+            (
+                vec![(1.0, 0.0), (2.0, 0.0), (3.0, 0.0)].into(),
+                serde_json::json!({"foot": 3.0, "bus": 5.0})
+            ),
+            (
+                vec![(1.0, 0.0), (1.0, 1.0)].into(),
+                serde_json::json!({"foot": 1.0, "bus": 5.0})
+            ),
+        ]
+    );
+
+    // Only sum some properties. Segments are still only joined where nothing branches off.
+    options.sum_keys = vec!["bus".to_string()];
+    assert_eq!(
+        simplify(overline(input(), &options).unwrap()),
+        vec![
+            (
+                vec![(0.0, 0.0), (1.0, 0.0)].into(),
+                serde_json::json!({"bus": 0.0})
+            ),
+            (
+                vec![(1.0, 0.0), (2.0, 0.0), (3.0, 0.0)].into(),
+                serde_json::json!({"bus": 5.0})
+            ),
+            (
+                vec![(1.0, 0.0), (1.0, 1.0)].into(),
+                serde_json::json!({"bus": 5.0})
+            ),
+        ]
+    );
+
+    options.sum_keys = vec!["name".to_string()];
+    assert!(overline(input(), &options).is_err());
+}
+
 // TODO Test zone names that look numeric and contain leading 0's
 
 fn sum_trips_input(csv_path: &str, keys: &[&str]) -> HashMap<String, f64> {